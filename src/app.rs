@@ -1,31 +1,491 @@
-use std::{collections::HashMap, path::Path, sync::Arc};
+use std::{
+    any::Any,
+    collections::HashMap,
+    ffi::OsStr,
+    fmt,
+    future::Future,
+    num::NonZeroU32,
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::RecvTimeoutError,
+        Arc, RwLock,
+    },
+    time::{Duration, Instant, SystemTime},
+};
 
-use serde::Deserialize;
-use tokio::sync::mpsc;
-use wasmtime::{Engine, Linker, Module, Store};
+use anyhow::{anyhow, Context};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    runtime::Handle,
+    sync::{broadcast, mpsc, oneshot},
+    task::JoinError,
+};
+use tokio_stream::{
+    wrappers::{BroadcastStream, WatchStream},
+    Stream, StreamExt, StreamMap,
+};
+use tracing::Instrument;
+use wasmtime::{
+    Config, Engine, ExternType, Instance, InstancePre, Linker, Module, ProfilingStrategy, Store,
+    Strategy, Val, ValType,
+};
 
 use crate::{
+    cgroup,
+    codec::{MqttCodec, MqttCodecRegistration},
+    compression::{uncompressed_path, Compression},
     debug_api,
+    error::{ConfigError, InitError, InstantiateTimeout, ReadyError},
+    event_queue::EventSender,
+    extension::HostExtension,
+    linking::{
+        explain_instantiate_error, extern_type_kind, extern_type_signature, reject_imports_from,
+    },
     module::{
-        initialize_mqtt_for_module, mqtt_event_loop_task, ModuleConfig, ModuleRuntimeConfig,
-        WasmModuleStore,
+        initialize_mqtt_for_module, mqtt_deadline_task, mqtt_event_loop_task, on_epoch_deadline,
+        wait_for_mqtt_connection, ExecutionMode, FeatureFlags, HostCallPolicy, LogBuffer,
+        LogRecord, ModuleConfig, ModuleControl, ModuleLimiter, ModuleRuntimeConfig,
+        MqttCredentials, MqttDeadline, PauseGate, Readiness, WasmModuleStore,
     },
-    mqtt_api,
+    mqtt_api::{self, MqttConnection, MqttConnectionStats, Subscriptions},
+    mqtt_mock::MockBroker,
+    runtime_api,
+    sink::{event_sink_task, EventSink},
+    snapshot::{unix_millis, ModuleSnapshot, MqttSnapshot, RuntimeSnapshot},
+    state::{load_restart_stats, save_restart_stats, ModuleRestartStats},
 };
 
+/// Interval at which the supervisor bumps the engine epoch. Every running store checks
+/// its watchdog once per tick, so this is also the watchdog resolution.
+const EPOCH_TICK: Duration = Duration::from_millis(50);
+
+/// Number of events buffered per subscriber before the oldest ones are overwritten.
+const APP_EVENT_CHANNEL_CAPACITY: usize = 1024;
+
+/// How long a module that asked for a restart gets to return from `start` before it is
+/// stopped.
+const SELF_RESTART_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// Self-requested restarts closer together than this are refused, so a module that
+/// keeps asking can't end up in a restart loop.
+const SELF_RESTART_MIN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often supervising loops tear down modules that finished on their own.
+pub(crate) const CLEANUP_INTERVAL: Duration = Duration::from_millis(100);
+
+const DEFAULT_LOG_BUFFER_LINES: usize = 100;
+
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+const DEFAULT_WAIT_FOR_MQTT_TIMEOUT: Duration = Duration::from_secs(10);
+
+const DEFAULT_PAUSE_ON_START_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// How often an idle `init_then_resident` module checks whether it was stopped.
+const RESIDENT_STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long [`InitializedAppContext::resubscribe`] waits for the broker's SubAck.
+const RESUBSCRIBE_TIMEOUT: Duration = Duration::from_secs(10);
+
 #[derive(Debug)]
 pub enum RuntimeEvent {
     RuntimeTaskStop,
+    /// Reconnect with these credentials, see [`InitializedAppContext::update_mqtt_credentials`].
+    UpdateCredentials(MqttCredentials),
+    /// Re-issue every tracked subscription, see [`InitializedAppContext::resubscribe`].
+    Resubscribe(oneshot::Sender<anyhow::Result<Vec<SubscriptionResult>>>),
+}
+
+/// The broker's answer for one topic filter, see [`InitializedAppContext::resubscribe`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubscriptionResult {
+    pub topic: String,
+    /// QoS granted by the broker, `None` if it rejected the subscription.
+    pub granted_qos: Option<rumqttc::QoS>,
+}
+
+#[derive(Debug)]
+pub enum ModuleExit {
+    Finished,
+    /// The module was interrupted by `stop_module` or `stop_group`.
+    Stopped,
+    Trapped(wasmtime::Trap),
+    /// The module went longer than `watchdog_timeout_ms` without a heartbeat or a
+    /// received MQTT message and was interrupted.
+    Watchdog,
+    /// `wait_for_mqtt` and `require_mqtt` are set and the MQTT connection wasn't
+    /// established in time, so `start` was never called.
+    MqttConnectTimeout,
+    /// `require_mqtt` is set and the MQTT connection was lost for longer than
+    /// `mqtt_reconnect_deadline_ms`.
+    MqttReconnectTimeout,
+    /// A host function panicked while the module was calling into it. Holds the panic
+    /// message. This is a host bug, not something the guest did wrong. Also reported by
+    /// [`InitializedAppContext::run_to_completion`] for a module it couldn't tear down.
+    HostError(String),
+    /// Instantiating the module took longer than `instantiate_timeout_ms`, so `start`
+    /// was never called.
+    InstantiateTimeout,
+}
+
+impl ModuleExit {
+    /// Process exit code for this outcome, see
+    /// [`InitializedAppContext::aggregate_exit_code`].
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ModuleExit::Finished | ModuleExit::Stopped => 0,
+            ModuleExit::Trapped(_) => 1,
+            ModuleExit::MqttConnectTimeout => 2,
+            ModuleExit::Watchdog => 3,
+            ModuleExit::MqttReconnectTimeout => 4,
+            ModuleExit::HostError(_) => 5,
+            ModuleExit::InstantiateTimeout => 6,
+        }
+    }
+}
+
+/// Called with the module name and panic message whenever a host function panics, see
+/// [`InitializedAppContext::set_host_panic_handler`].
+pub type HostPanicHandler = Arc<dyn Fn(&str, &str) + Send + Sync>;
+
+/// Lifecycle events published by the runtime, see [`InitializedAppContext::events`].
+#[derive(Debug, Clone)]
+pub enum AppEvent {
+    ModuleStarted {
+        name: String,
+        reason: StartReason,
+    },
+    ModuleStartFailed {
+        name: String,
+        error: String,
+    },
+    /// [`InitializedAppContext::canary_start`] rejected the module, which wasn't started.
+    ModuleCanaryFailed {
+        name: String,
+        error: String,
+    },
+    ModuleFinished {
+        name: String,
+    },
+    /// The module's binary was swapped by [`InitializedAppContext::replace_module_binary`].
+    ModuleReplaced {
+        name: String,
+    },
+    ModuleStopped {
+        name: String,
+    },
+    ModuleTrapped {
+        name: String,
+        trap: String,
+    },
+    ModuleWatchdogExpired {
+        name: String,
+    },
+    ModuleHostError {
+        name: String,
+        error: String,
+    },
+    MqttConnected {
+        name: String,
+    },
+    MqttDisconnected {
+        name: String,
+        error: String,
+    },
+    /// The module's MQTT connection is being re-established on purpose, e.g. after
+    /// [`InitializedAppContext::update_mqtt_credentials`]. Followed by `MqttConnected`.
+    MqttReconnecting {
+        name: String,
+        reason: String,
+    },
+    /// Stopping the module's MQTT connection timed out (`drain_timeout_ms`) with `pending`
+    /// publishes not sent or not acknowledged, which are lost.
+    MqttDrainIncomplete {
+        name: String,
+        pending: usize,
+    },
+    /// A codec registered through [`InitializedAppContext::register_mqtt_codec`] rejected
+    /// an incoming message, which was dropped.
+    MqttMessageRejected {
+        name: String,
+        topic: String,
+        error: String,
+    },
+    /// MQTT missed `mqtt_connect_deadline_ms` or `mqtt_reconnect_deadline_ms`. The module
+    /// is stopped if it has `require_mqtt` set.
+    MqttDeadlineExpired {
+        name: String,
+        deadline: MqttDeadline,
+    },
+    /// A module instance's linear memory grew past `memory_warn_bytes`. Published once
+    /// per instance; `used` is the size in bytes after the growth.
+    MemoryWarning {
+        name: String,
+        used: usize,
+    },
+    /// The module's MQTT runtime failed to start and `require_mqtt` is off, so the module
+    /// runs without MQTT.
+    MqttDegraded {
+        name: String,
+        error: String,
+    },
+    /// Starting the module could exceed `max_background_tasks` with `running` tasks
+    /// already running, so the start is deferred until enough of them have ended.
+    TaskLimitReached {
+        name: String,
+        running: usize,
+        limit: usize,
+    },
+    /// The subscriber fell behind and `skipped` events were dropped for it.
+    Lagged {
+        skipped: u64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StartReason {
+    /// First start of the module since the context was initialized.
+    Initial,
+    Restart {
+        /// The module asked for the restart through `request-restart`.
+        self_requested: bool,
+        /// The reason the module gave, if self-requested.
+        reason: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskKind {
+    /// Runs the module's `start` entrypoint.
+    Entrypoint,
+    MqttEventLoop,
+    /// Flushes batched MQTT publishes, see `batch_window_ms`.
+    MqttPublishBatch,
+    /// Enforces `mqtt_connect_deadline_ms` and `mqtt_reconnect_deadline_ms`.
+    MqttDeadline,
+}
+
+/// A tokio task spawned for a running module, see
+/// [`InitializedAppContext::background_tasks`].
+#[derive(Debug, Clone)]
+pub struct TaskInfo {
+    pub kind: TaskKind,
+    pub spawned_at: SystemTime,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModuleStatus {
+    NotStarted,
+    /// `enabled = false` in the module's config; it won't be started.
+    Disabled,
+    /// The module file was missing at startup and `on_missing_module` is `skip`.
+    Unavailable,
+    /// Held before its entrypoint by `pause_on_start`, see
+    /// [`InitializedAppContext::continue_module`].
+    WaitingToStart,
+    /// The last attempt to start the module failed and it isn't running.
+    StartFailed,
+    Running,
+    /// Suspended by [`InitializedAppContext::pause_all`].
+    Paused,
+}
+
+/// An import of a compiled module, see [`InitializedAppContext::module_imports`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ImportInfo {
+    pub module: String,
+    pub name: String,
+    /// `function`, `global`, `table` or `memory`.
+    pub kind: &'static str,
+    pub signature: String,
+}
+
+/// An export of a compiled module, see [`InitializedAppContext::module_exports`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ExportInfo {
+    pub name: String,
+    /// `function`, `global`, `table` or `memory`.
+    pub kind: &'static str,
+    pub signature: String,
+}
+
+/// Approximate host memory used by a module, see [`InitializedAppContext::memory_usage`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModuleMemoryUsage {
+    /// Size of the compiled module's image in memory, i.e. its machine code and
+    /// read-only data.
+    pub compiled_size: usize,
+    /// Initial size of the module's exported linear memories, capped at
+    /// `max_memory_bytes`. This is what every instance commits at least; the virtual
+    /// address space Wasmtime reserves per memory is much larger.
+    pub instance_memory: usize,
+}
+
+#[derive(Deserialize, JsonSchema, Default)]
+pub struct AppDefaults {
+    #[serde(default)]
+    pub runtime: ModuleRuntimeConfig,
+}
+
+/// Engine-wide settings. These apply to every module since all of them share one
+/// `Engine`.
+#[derive(Deserialize, JsonSchema, Clone, Debug, Default)]
+pub struct EngineConfig {
+    /// Enables the memory64 proposal for modules that need more than 4 GiB of linear
+    /// memory. 64-bit memories can't rely on guard pages to elide bounds checks, so every
+    /// memory access in such a module carries an explicit check and runs noticeably
+    /// slower than with a 32-bit memory.
+    #[serde(default)]
+    pub memory64: bool,
+    #[serde(default)]
+    pub compiler: Compiler,
+    /// Emits the symbol information `perf` (`jitdump`) or VTune (`vtune`) need to
+    /// attribute samples to wasm functions. `jitdump` is Linux only and has to be enabled
+    /// when recording, e.g. `perf record -k mono`, followed by `perf inject --jit`. `vtune`
+    /// needs the VTune profiler's collector on x86_64. Off by default.
+    #[serde(default)]
+    pub profiler: Profiler,
+}
+
+/// The derived `HashMap` deserialization, except that a module name occurring twice fails
+/// instead of the last entry silently winning. `toml` already rejects a redefined
+/// `[modules.x]` table while parsing, before this visitor sees the map, so in practice it
+/// only fires for JSON, whose parser hands duplicate keys through.
+fn deserialize_modules<'de, D>(deserializer: D) -> Result<HashMap<String, ModuleConfig>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    struct ModulesVisitor;
+
+    impl<'de> serde::de::Visitor<'de> for ModulesVisitor {
+        type Value = HashMap<String, ModuleConfig>;
+
+        fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+            formatter.write_str("a table of modules")
+        }
+
+        fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+        where
+            A: serde::de::MapAccess<'de>,
+        {
+            let mut modules = HashMap::new();
+
+            while let Some((module_name, module_config)) =
+                map.next_entry::<String, ModuleConfig>()?
+            {
+                if modules.contains_key(&module_name) {
+                    return Err(serde::de::Error::custom(format!(
+                        "module '{}' is defined more than once",
+                        module_name
+                    )));
+                }
+
+                modules.insert(module_name, module_config);
+            }
+
+            Ok(modules)
+        }
+    }
+
+    deserializer.deserialize_map(ModulesVisitor)
+}
+
+/// See [`EngineConfig::profiler`].
+#[derive(Deserialize, JsonSchema, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Profiler {
+    #[default]
+    None,
+    JitDump,
+    /// `/tmp/perf-<pid>.map` symbol files. Not available in the Wasmtime version this
+    /// crate is built against; selecting it fails engine creation.
+    PerfMap,
+    VTune,
+}
+
+/// Code generator used to compile modules.
+#[derive(Deserialize, JsonSchema, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compiler {
+    #[default]
+    Cranelift,
+    /// Baseline compiler with much faster compilation but slower code. Not available in
+    /// the Wasmtime version this crate is built against; selecting it fails engine
+    /// creation.
+    Winch,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 pub struct AppConfig {
+    #[serde(default)]
+    pub engine: EngineConfig,
+    #[serde(default)]
+    pub defaults: AppDefaults,
+    /// Glob pattern (e.g. `modules/*.wasm`) of module files to load in addition to the
+    /// ones listed under `modules`. Discovered modules are named after their file stem
+    /// and use the default runtime config. An explicit `modules` entry with the same name
+    /// takes precedence and replaces the discovered module entirely.
+    pub modules_glob: Option<String>,
+    /// Delay between launching consecutive modules in `run_all_modules`/`run_group`, to
+    /// avoid a burst of CPU load and MQTT connections on startup.
+    pub start_stagger_ms: Option<u64>,
+    /// File the per-module restart statistics are persisted to, so crash history survives
+    /// runtime restarts. Persistence is off when unset.
+    pub state_file: Option<Box<Path>>,
+    /// Settings for the HTTP control plane, see [`crate::rest_control::serve`].
+    #[cfg(feature = "rest-control")]
+    pub rest_control: Option<crate::rest_control::RestControlConfig>,
+    /// Time budget for `initialize_modules` plus the initial start of all modules in
+    /// `run_until_signal`/`run_to_completion`, after which startup is aborted with
+    /// [`InitError::StartupTimeout`]. Unlimited when unset.
+    pub startup_deadline_ms: Option<u64>,
+    /// Upper bound for the tokio tasks of all running modules together, see
+    /// [`InitializedAppContext::background_tasks`]. A start that could exceed it is
+    /// deferred until enough tasks have ended, announced by [`AppEvent::TaskLimitReached`].
+    /// Unlimited when unset.
+    pub max_background_tasks: Option<usize>,
+    /// Retry reading module files that fail with an IO error, e.g. on a network
+    /// filesystem. Files are read once when unset.
+    pub load_retry: Option<LoadRetryConfig>,
+    /// What to do when a module file doesn't exist at startup. Defaults to `fail`.
+    #[serde(default)]
+    pub on_missing_module: OnMissingModule,
+    /// A module name occurring twice in one file is an error. Across the files of
+    /// [`AppConfig::from_layered`] it's an override.
+    #[serde(default, deserialize_with = "deserialize_modules")]
     pub modules: HashMap<String, ModuleConfig>,
 }
 
+#[derive(Deserialize, JsonSchema, Clone, Debug)]
+pub struct LoadRetryConfig {
+    /// Total number of reads, including the first one.
+    pub attempts: NonZeroU32,
+    /// Delay before the first retry, doubled for every further one.
+    pub delay_ms: u64,
+}
+
+#[derive(Deserialize, JsonSchema, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OnMissingModule {
+    /// Abort startup.
+    #[default]
+    Fail,
+    /// Log a warning and leave the module out; it reports [`ModuleStatus::Unavailable`].
+    Skip,
+}
+
 pub struct UninitializedModule<C> {
     bytes: Box<[u8]>,
+    /// `bytes` is a `.cwasm` artifact from [`precompile_module`].
+    precompiled: bool,
+    group: Option<String>,
+    enabled: bool,
     runtime_config: C,
+    /// Spans the module's whole lifetime, see [`ModuleData::span`].
+    span: tracing::Span,
 }
 
 #[derive(Clone)]
@@ -38,72 +498,637 @@ pub struct InitializedModule<T, C> {
 
 pub struct UninitializedAppContext {
     modules: HashMap<String, UninitializedModule<ModuleRuntimeConfig>>,
+    /// Modules skipped under `on_missing_module = "skip"`, with the reason.
+    unavailable_modules: HashMap<String, String>,
+    engine_config: EngineConfig,
+    runtime_handle: Option<Handle>,
+    state_file: Option<Box<Path>>,
+    start_stagger: Duration,
+    startup_deadline: Option<Duration>,
+    max_background_tasks: Option<usize>,
+    host_extension: Option<Arc<dyn HostExtension>>,
 }
 
 struct MqttEventLoopTaskInfo {
     pub runtime_event_sender: tokio::sync::mpsc::Sender<RuntimeEvent>,
+    /// Feeds events to the guest as if they came from the broker.
+    #[cfg_attr(not(feature = "test-util"), allow(dead_code))]
+    pub event_channel_sender: EventSender,
     pub task_handle: tokio::task::JoinHandle<anyhow::Result<()>>,
 }
 
 struct ModuleRuntime {
-    module_task_handle: tokio::task::JoinHandle<Result<(), wasmtime::Trap>>,
+    module_task_handle: tokio::task::JoinHandle<ModuleExit>,
     module_mqtt_event_loop_task_info: Option<MqttEventLoopTaskInfo>,
+    control: Arc<ModuleControl>,
+    mqtt_stats: Option<Arc<MqttConnectionStats>>,
+    started_at: SystemTime,
+    tasks: Vec<TaskInfo>,
+    /// Tasks besides the entrypoint and MQTT event loop, aborted on teardown.
+    auxiliary_task_handles: Vec<tokio::task::JoinHandle<()>>,
+    /// `call_export` calls for the instance of an `init_then_resident` module.
+    resident_call_sender: Option<std::sync::mpsc::Sender<ExportCall>>,
+    mqtt_subscriptions: Option<Subscriptions>,
 }
 
 struct ModuleData {
     module_template: InitializedModule<WasmModuleStore, ModuleRuntimeConfig>,
+    group: Option<String>,
+    enabled: bool,
     runtime: Option<ModuleRuntime>,
+    start_error: Option<String>,
+    canary_error: Option<String>,
+    /// Subscriptions for the next start to restore, see
+    /// [`InitializedAppContext::replace_module_binary`].
+    carried_subscriptions: Option<HashMap<String, rumqttc::QoS>>,
+    /// `module` span with the module name, parent of the spans for loading, compiling,
+    /// every start and every `call_export` call.
+    span: tracing::Span,
+    restart_stats: ModuleRestartStats,
+    feature_flags: FeatureFlags,
+    logs: Arc<LogBuffer>,
+    denied_host_calls: Arc<AtomicU64>,
+    /// Kept under `retain_module_bytes`, see [`InitializedAppContext::module_bytes`].
+    module_bytes: Option<Box<[u8]>>,
+    persistent_instance: Option<std::sync::mpsc::Sender<ExportCall>>,
+    memory_usage: ModuleMemoryUsage,
+    start_reason: Option<StartReason>,
+    last_exit_code: Option<i32>,
+    pending_self_restart: Option<String>,
+    last_self_restart: Option<Instant>,
+}
+
+/// A `call_export` call queued for a module's persistent instance.
+struct ExportCall {
+    /// Entered by the thread executing the call.
+    span: tracing::Span,
+    func_name: String,
+    args: Vec<Val>,
+    result_sender: oneshot::Sender<anyhow::Result<Vec<Val>>>,
 }
 
+/// All background work is spawned on the tokio runtime the context was initialized with
+/// (see [`UninitializedAppContext::with_runtime_handle`]): the epoch supervisor and the
+/// MQTT event loops as regular tasks, and each module's entrypoint on the runtime's
+/// blocking pool since guest code never yields. Both current-thread and multi-thread
+/// runtimes work; no part of the crate relies on `block_in_place`.
 pub struct InitializedAppContext {
     modules: HashMap<String, ModuleData>,
+    epoch_supervisor_task_handle: tokio::task::JoinHandle<()>,
+    app_event_sender: broadcast::Sender<AppEvent>,
+    pause_gate: Arc<PauseGate>,
+    runtime_handle: Handle,
+    engine: Arc<Engine>,
+    state_file: Option<Box<Path>>,
+    mqtt_codecs: Arc<Vec<MqttCodecRegistration>>,
+    mock_broker: MockBroker,
+    start_stagger: Duration,
+    host_panic_handler: Option<HostPanicHandler>,
+    event_sink_task_handles: Vec<tokio::task::JoinHandle<()>>,
+    unavailable_modules: HashMap<String, String>,
+    /// Taken by the first `start_up`.
+    startup_deadline: Option<Instant>,
+    max_background_tasks: Option<usize>,
+    /// Modules waiting for room under `max_background_tasks`, in the order they were
+    /// started.
+    deferred_starts: Vec<String>,
+    host_extension: Option<Arc<dyn HostExtension>>,
 }
 
 impl AppConfig {
-    pub fn from_app_config_file(path: impl AsRef<Path>) -> anyhow::Result<AppConfig> {
-        let config_file_contents = std::fs::read_to_string(path)?;
+    pub fn from_app_config_file(path: impl AsRef<Path>) -> Result<AppConfig, ConfigError> {
+        let path = path.as_ref();
+        let config_file_contents =
+            std::fs::read_to_string(path).map_err(|source| ConfigError::Io {
+                path: path.into(),
+                source,
+            })?;
+
+        toml::from_str(&config_file_contents).map_err(|source| ConfigError::Parse {
+            path: path.into(),
+            source,
+        })
+    }
+
+    /// Parses a whole config from `reader`, e.g. one piped in. The format has to be given
+    /// since there's no file extension to go by.
+    pub fn from_reader(
+        mut reader: impl std::io::Read,
+        format: ConfigFormat,
+    ) -> Result<AppConfig, ConfigError> {
+        let mut contents = String::new();
+        reader
+            .read_to_string(&mut contents)
+            .map_err(ConfigError::Read)?;
+
+        let parsed: Result<AppConfig, Box<dyn std::error::Error + Send + Sync>> = match format {
+            ConfigFormat::Toml => toml::from_str(&contents).map_err(Into::into),
+            ConfigFormat::Json => serde_json::from_str(&contents).map_err(Into::into),
+        };
+
+        parsed.map_err(|source| ConfigError::Format { format, source })
+    }
+
+    /// [`Self::from_reader`] on all of stdin.
+    pub fn from_stdin(format: ConfigFormat) -> Result<AppConfig, ConfigError> {
+        AppConfig::from_reader(std::io::stdin().lock(), format)
+    }
+
+    /// Module file of every module the config defines, explicitly or through
+    /// `modules_glob`.
+    pub fn module_paths(&self) -> Result<HashMap<String, Box<Path>>, ConfigError> {
+        let mut module_paths: HashMap<String, Box<Path>> = match &self.modules_glob {
+            Some(modules_glob) => discover_modules(modules_glob)?
+                .into_iter()
+                .map(|(module_name, module_config)| (module_name, module_config.wasm_module_path))
+                .collect(),
+            None => HashMap::new(),
+        };
+
+        for (module_name, module_config) in &self.modules {
+            module_paths.insert(module_name.clone(), module_config.wasm_module_path.clone());
+        }
+
+        Ok(module_paths)
+    }
+
+    /// Loads every file in `paths` and deep-merges them in order before parsing the result.
+    /// Tables, including the `modules` map and each module's settings, are merged key by
+    /// key with later files winning; any other value, arrays included, is replaced as a
+    /// whole. An overlay can take a module of the base config out of rotation with
+    /// `enabled = false`.
+    pub fn from_layered(paths: &[impl AsRef<Path>]) -> Result<AppConfig, ConfigError> {
+        let mut merged = toml::Value::Table(toml::value::Table::new());
+
+        for path in paths {
+            let path = path.as_ref();
+            let layer_contents =
+                std::fs::read_to_string(path).map_err(|source| ConfigError::Io {
+                    path: path.into(),
+                    source,
+                })?;
+            let layer: toml::Value =
+                toml::from_str(&layer_contents).map_err(|source| ConfigError::Parse {
+                    path: path.into(),
+                    source,
+                })?;
+
+            merge_toml(&mut merged, layer);
+        }
+
+        merged
+            .try_into()
+            .map_err(|e| ConfigError::InvalidValue(format!("merged config layers: {}", e)))
+    }
+}
+
+fn merge_toml(base: &mut toml::Value, overlay: toml::Value) {
+    match (base, overlay) {
+        (toml::Value::Table(base), toml::Value::Table(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(base_value) => merge_toml(base_value, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// Syntax of a config read by [`AppConfig::from_reader`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Toml,
+    Json,
+}
+
+impl fmt::Display for ConfigFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigFormat::Toml => f.write_str("toml"),
+            ConfigFormat::Json => f.write_str("json"),
+        }
+    }
+}
+
+impl FromStr for ConfigFormat {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<ConfigFormat, ConfigError> {
+        match s {
+            "toml" => Ok(ConfigFormat::Toml),
+            "json" => Ok(ConfigFormat::Json),
+            _ => Err(ConfigError::InvalidValue(format!(
+                "unknown config format '{}', expected 'toml' or 'json'",
+                s
+            ))),
+        }
+    }
+}
+
+/// JSON Schema of the app config file, derived from [`AppConfig`] and the types nested
+/// in it. Also printed by the `config-schema` binary.
+pub fn config_schema() -> serde_json::Value {
+    serde_json::to_value(schemars::schema_for!(AppConfig))
+        .expect("JSON schema is always serializable")
+}
+
+fn unsupported_by_wasmtime(setting: &str) -> ConfigError {
+    ConfigError::InvalidValue(format!(
+        "{} is not supported by this Wasmtime version",
+        setting
+    ))
+}
+
+impl EngineConfig {
+    pub fn to_wasmtime_config(&self) -> Result<Config, ConfigError> {
+        let strategy = match self.compiler {
+            Compiler::Cranelift => Strategy::Cranelift,
+            Compiler::Winch => return Err(unsupported_by_wasmtime("compiler 'winch'")),
+        };
+        let profiler = match self.profiler {
+            Profiler::None => ProfilingStrategy::None,
+            Profiler::JitDump => ProfilingStrategy::JitDump,
+            Profiler::PerfMap => return Err(unsupported_by_wasmtime("profiler 'perfmap'")),
+            Profiler::VTune => ProfilingStrategy::VTune,
+        };
+
+        let mut config = Config::new();
+        config
+            .epoch_interruption(true)
+            .wasm_memory64(self.memory64)
+            .strategy(strategy)
+            .profiler(profiler);
+
+        Ok(config)
+    }
+}
+
+/// Wasmtime doesn't expose an engine's settings, but loading a precompiled module checks
+/// them against the ones it was compiled with. So an empty module compiled with the
+/// expected config is loaded into `engine`, which fails if the two disagree on anything
+/// that affects code generation, such as epoch interruption or enabled proposals.
+fn check_engine_compatibility(engine: &Engine, engine_config: &EngineConfig) -> anyhow::Result<()> {
+    let expected_engine = Engine::new(&engine_config.to_wasmtime_config()?)?;
+    let probe = Module::new(&expected_engine, "(module)")?.serialize()?;
+
+    // Safety: `probe` was just produced by `Module::serialize`.
+    unsafe { Module::deserialize(engine, probe) }.map_err(|e| {
+        anyhow!(
+            "provided engine is incompatible with the engine config: {}",
+            e
+        )
+    })?;
+
+    Ok(())
+}
+
+/// Compiles `wasm` ahead of time into the `.cwasm` format `initialize_modules` loads
+/// module files with that extension from. `engine` must be created from the same
+/// [`EngineConfig`] the app will run with, otherwise loading the artifact fails.
+pub fn precompile_module(engine: &Engine, wasm: &[u8]) -> anyhow::Result<Vec<u8>> {
+    Module::new(engine, wasm)?.serialize()
+}
+
+fn is_precompiled(wasm_module_path: &Path) -> bool {
+    uncompressed_path(wasm_module_path).extension() == Some(OsStr::new("cwasm"))
+}
+
+/// Reads `path`, retrying IO errors according to `load_retry`.
+fn read_with_retry(
+    module_name: &str,
+    path: &Path,
+    load_retry: Option<&LoadRetryConfig>,
+) -> std::io::Result<Vec<u8>> {
+    let attempts = load_retry.map_or(1, |load_retry| load_retry.attempts.get());
+    let mut delay = load_retry.map_or(Duration::ZERO, |load_retry| {
+        Duration::from_millis(load_retry.delay_ms)
+    });
+    let mut attempt = 1;
+
+    loop {
+        match std::fs::read(path) {
+            Err(e) if attempt < attempts => {
+                tracing::warn!(
+                    "Reading module '{}' from '{}' failed (attempt {} of {}), retrying in \
+                     {:?}: {}",
+                    module_name,
+                    path.display(),
+                    attempt,
+                    attempts,
+                    delay,
+                    e
+                );
+
+                std::thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Reads a module's binary, decompressing gzip and zstd files (see
+/// [`Compression::detect`]) and assembling `.wat` text. Only reading the file is retried;
+/// decompression and assembly errors fail right away.
+fn load_module_bytes(
+    module_name: &str,
+    wasm_module_path: &Path,
+    load_retry: Option<&LoadRetryConfig>,
+) -> Result<Vec<u8>, InitError> {
+    let load_error = |source: anyhow::Error| InitError::ModuleLoad {
+        module: module_name.to_string(),
+        path: wasm_module_path.into(),
+        source,
+    };
+
+    let bytes = read_with_retry(module_name, wasm_module_path, load_retry)
+        .map_err(|e| load_error(e.into()))?;
+    let bytes = match Compression::detect(wasm_module_path, &bytes) {
+        Some(compression) => {
+            compression
+                .decompress(&bytes)
+                .map_err(|source| InitError::ModuleDecompress {
+                    module: module_name.to_string(),
+                    path: wasm_module_path.into(),
+                    compression,
+                    source,
+                })?
+        }
+        None => bytes,
+    };
+
+    if uncompressed_path(wasm_module_path).extension() == Some(OsStr::new("wat")) {
+        wat::parse_bytes(&bytes)
+            .map(|wasm| wasm.into_owned())
+            .map_err(|mut e| {
+                // Gives the error the file name next to the line and column.
+                e.set_path(wasm_module_path);
+                load_error(e.into())
+            })
+    } else {
+        Ok(bytes)
+    }
+}
 
-        Ok(toml::from_str(&config_file_contents)?)
+/// Builds a module config for every file matching `modules_glob`, named after the file
+/// stem and using the default runtime config.
+fn discover_modules(modules_glob: &str) -> Result<HashMap<String, ModuleConfig>, ConfigError> {
+    let mut discovered_modules = HashMap::new();
+
+    let wasm_module_paths = glob::glob(modules_glob).map_err(|e| {
+        ConfigError::InvalidValue(format!("modules_glob '{}': {}", modules_glob, e))
+    })?;
+
+    for wasm_module_path in wasm_module_paths {
+        let wasm_module_path = wasm_module_path.map_err(|e| ConfigError::Io {
+            path: e.path().into(),
+            source: e.into_error(),
+        })?;
+        let module_name = uncompressed_path(&wasm_module_path)
+            .file_stem()
+            .and_then(|file_stem| file_stem.to_str())
+            .ok_or_else(|| {
+                ConfigError::InvalidValue(format!(
+                    "Can't derive a module name from '{}'",
+                    wasm_module_path.display()
+                ))
+            })?
+            .to_string();
+
+        let module_config = ModuleConfig {
+            runtime: ModuleRuntimeConfig::default(),
+            wasm_module_path: wasm_module_path.into_boxed_path(),
+            group: None,
+            enabled: true,
+        };
+
+        if let Some(previous_module_config) =
+            discovered_modules.insert(module_name.clone(), module_config)
+        {
+            return Err(ConfigError::InvalidValue(format!(
+                "modules_glob matches more than one file for module '{}', including '{}'",
+                module_name,
+                previous_module_config.wasm_module_path.display()
+            )));
+        }
     }
+
+    Ok(discovered_modules)
 }
 
 impl UninitializedAppContext {
-    pub fn new(config: &AppConfig) -> anyhow::Result<UninitializedAppContext> {
-        let modules: Result<HashMap<String, UninitializedModule<ModuleRuntimeConfig>>, _> =
-            config
-                .modules
-                .iter()
-                .map(
-                    |(module_name, module_config)| -> std::io::Result<(String, UninitializedModule<ModuleRuntimeConfig>)> {
-                        Ok((
-                            module_name.clone(),
-                            UninitializedModule::<ModuleRuntimeConfig> {
-                                bytes: std::fs::read(&module_config.wasm_module_path)?
-                                    .into_boxed_slice(),
-                                runtime_config: module_config.runtime.clone(),
-                            },
-                        ))
-                    },
-                )
-                .collect();
+    pub fn new(config: &AppConfig) -> Result<UninitializedAppContext, InitError> {
+        let discovered_modules = match &config.modules_glob {
+            Some(modules_glob) => discover_modules(modules_glob)?,
+            None => HashMap::new(),
+        };
+
+        let mut modules = HashMap::new();
+        let mut unavailable_modules = HashMap::new();
+
+        for (module_name, module_config) in discovered_modules
+            .iter()
+            .filter(|(module_name, _)| !config.modules.contains_key(*module_name))
+            .chain(config.modules.iter())
+        {
+            let wasm_module_path = &module_config.wasm_module_path;
+            let span = tracing::info_span!("module", name = %module_name);
+
+            if !wasm_module_path.exists() {
+                let error = InitError::ModuleNotFound {
+                    module: module_name.clone(),
+                    path: wasm_module_path.clone(),
+                };
+
+                match config.on_missing_module {
+                    OnMissingModule::Skip => {
+                        tracing::warn!("{}, skipping it", error);
+                        unavailable_modules.insert(module_name.clone(), error.to_string());
+                        continue;
+                    }
+                    OnMissingModule::Fail => return Err(error),
+                }
+            }
+
+            let bytes = {
+                let _load = tracing::info_span!(parent: &span, "load").entered();
+
+                load_module_bytes(module_name, wasm_module_path, config.load_retry.as_ref())?
+            };
+
+            let runtime_config = module_config.runtime.merged_with(&config.defaults.runtime);
+
+            runtime_config.check_complete(module_name)?;
+            modules.insert(
+                module_name.clone(),
+                UninitializedModule::<ModuleRuntimeConfig> {
+                    bytes: bytes.into_boxed_slice(),
+                    precompiled: is_precompiled(wasm_module_path),
+                    group: module_config.group.clone(),
+                    enabled: module_config.enabled,
+                    runtime_config,
+                    span,
+                },
+            );
+        }
+
+        if modules.is_empty() && unavailable_modules.is_empty() {
+            tracing::warn!("no modules configured");
+        }
+
+        Ok(UninitializedAppContext {
+            modules,
+            unavailable_modules,
+            engine_config: config.engine.clone(),
+            runtime_handle: None,
+            state_file: config.state_file.clone(),
+            start_stagger: config
+                .start_stagger_ms
+                .map_or(Duration::ZERO, Duration::from_millis),
+            startup_deadline: config.startup_deadline_ms.map(Duration::from_millis),
+            max_background_tasks: config.max_background_tasks,
+            host_extension: None,
+        })
+    }
 
-        Ok(UninitializedAppContext { modules: modules? })
+    /// Spawns the runtime's tasks on `runtime_handle` instead of the runtime
+    /// `initialize_modules` is called from.
+    pub fn with_runtime_handle(mut self, runtime_handle: Handle) -> UninitializedAppContext {
+        self.runtime_handle = Some(runtime_handle);
+        self
     }
 
-    pub fn initialize_modules(self) -> anyhow::Result<InitializedAppContext> {
-        let engine = Arc::new(Engine::default());
+    /// Adds `extension`'s host functions to every module's linker and gives each of their
+    /// stores the extension's state, see [`HostExtension`].
+    pub fn with_host_extension(
+        mut self,
+        extension: Arc<dyn HostExtension>,
+    ) -> UninitializedAppContext {
+        self.host_extension = Some(extension);
+        self
+    }
+
+    /// Must be called from within a tokio runtime unless a handle was provided through
+    /// [`UninitializedAppContext::with_runtime_handle`].
+    pub fn initialize_modules(self) -> Result<InitializedAppContext, InitError> {
+        let engine = Arc::new(
+            Engine::new(&self.engine_config.to_wasmtime_config()?).map_err(InitError::Engine)?,
+        );
+
+        self.initialize_with(engine)
+    }
+
+    /// Like [`UninitializedAppContext::initialize_modules`], but compiles and runs the
+    /// modules in `engine` instead of creating one, e.g. to share an engine between
+    /// several contexts. The engine must have been created with a config compatible with
+    /// the app's `engine` section (see [`EngineConfig::to_wasmtime_config`]); in
+    /// particular epoch interruption has to be enabled.
+    pub fn initialize_modules_with_engine(
+        self,
+        engine: Arc<Engine>,
+    ) -> Result<InitializedAppContext, InitError> {
+        check_engine_compatibility(&engine, &self.engine_config).map_err(InitError::Engine)?;
+
+        self.initialize_with(engine)
+    }
+
+    fn initialize_with(self, engine: Arc<Engine>) -> Result<InitializedAppContext, InitError> {
+        let startup_deadline = self
+            .startup_deadline
+            .map(|startup_deadline| Instant::now() + startup_deadline);
+        let runtime_handle = self.runtime_handle.clone().unwrap_or_else(Handle::current);
+        let mut restart_stats = match &self.state_file {
+            Some(state_file) => {
+                load_restart_stats(state_file).map_err(|source| InitError::StateFile {
+                    path: state_file.clone(),
+                    source,
+                })?
+            }
+            None => HashMap::new(),
+        };
+
+        // A compilation in progress can't be interrupted, so the deadline is checked
+        // between modules.
+        let mut pending_module_names: Vec<String> = self.modules.keys().cloned().collect();
 
         let initialized_modules: Result<HashMap<String, ModuleData>, _> = self
             .modules
             .into_iter()
             .map(
-                |(module_name, module)| -> anyhow::Result<(String, ModuleData)> {
+                |(module_name, module)| -> Result<(String, ModuleData), InitError> {
+                    if startup_deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+                        return Err(InitError::StartupTimeout {
+                            pending_modules: pending_module_names.clone(),
+                        });
+                    }
+
+                    let _compile = tracing::info_span!(parent: &module.span, "compile").entered();
                     let mut linker = Linker::<WasmModuleStore>::new(&engine);
 
-                    let compiled_module = Module::from_binary(&engine, &module.bytes)?;
+                    let compiled_module = if module.precompiled {
+                        // Safety: `.cwasm` files are trusted to come from
+                        // `precompile_module`, like the `.wasm` files are trusted to be
+                        // the intended modules.
+                        unsafe { Module::deserialize(&engine, &module.bytes) }.map_err(|e| {
+                            InitError::Compile {
+                                module: module_name.clone(),
+                                source: e
+                                    .context("precompiled module doesn't match the engine config"),
+                            }
+                        })?
+                    } else {
+                        Module::from_binary(&engine, &module.bytes).map_err(|source| {
+                            InitError::Compile {
+                                module: module_name.clone(),
+                                source,
+                            }
+                        })?
+                    };
+                    let memory_usage = ModuleMemoryUsage {
+                        compiled_size: compiled_module.image_range().len(),
+                        instance_memory: instance_memory(
+                            &compiled_module,
+                            module.runtime_config.max_memory_bytes,
+                        ),
+                    };
+
+                    let linker_error = |source: anyhow::Error| InitError::Linker {
+                        module: module_name.clone(),
+                        source,
+                    };
+                    mqtt_api::add_to_linker(&mut linker, |s| s).map_err(linker_error)?;
+                    if module.runtime_config.debug_api_enabled.unwrap_or(true) {
+                        debug_api::add_to_linker(&mut linker, |s| s).map_err(linker_error)?;
+                    }
+                    runtime_api::add_to_linker(&mut linker, |s| s).map_err(linker_error)?;
+                    if let Some(host_extension) = &self.host_extension {
+                        host_extension
+                            .add_to_linker(&mut linker)
+                            .map_err(linker_error)?;
+                    }
+
+                    let module_restart_stats =
+                        restart_stats.remove(&module_name).unwrap_or_default();
+                    let feature_flags = Arc::new(RwLock::new(
+                        module
+                            .runtime_config
+                            .feature_flags
+                            .clone()
+                            .unwrap_or_default(),
+                    ));
+
+                    pending_module_names.retain(|pending| *pending != module_name);
 
-                    mqtt_api::add_to_linker(&mut linker, |s| &mut s.mqtt_connection)?;
-                    debug_api::add_to_linker(&mut linker, |s| s)?;
+                    let module_bytes = module
+                        .runtime_config
+                        .retain_module_bytes
+                        .unwrap_or(false)
+                        .then(|| module.bytes);
 
                     Ok((
                         module_name,
@@ -117,113 +1142,2389 @@ impl UninitializedAppContext {
                                 engine: engine.clone(),
                                 runtime_config: module.runtime_config,
                             },
+                            group: module.group,
+                            enabled: module.enabled,
+                            restart_stats: module_restart_stats,
                             runtime: None,
+                            start_error: None,
+                            canary_error: None,
+                            carried_subscriptions: None,
+                            span: module.span,
+                            feature_flags,
+                            logs: Arc::new(LogBuffer::new(
+                                module
+                                    .runtime_config
+                                    .log_buffer_lines
+                                    .unwrap_or(DEFAULT_LOG_BUFFER_LINES),
+                            )),
+                            denied_host_calls: Arc::new(AtomicU64::new(0)),
+                            module_bytes,
+                            persistent_instance: None,
+                            memory_usage,
+                            start_reason: None,
+                            last_exit_code: None,
+                            pending_self_restart: None,
+                            last_self_restart: None,
                         },
                     ))
                 },
             )
             .collect();
 
+        let epoch_supervisor_task_handle =
+            runtime_handle.spawn(epoch_supervisor_task(engine.clone()));
+        let (app_event_sender, _) = broadcast::channel(APP_EVENT_CHANNEL_CAPACITY);
+
         Ok(InitializedAppContext {
             modules: initialized_modules?,
+            epoch_supervisor_task_handle,
+            app_event_sender,
+            pause_gate: Arc::new(PauseGate::default()),
+            runtime_handle,
+            engine,
+            state_file: self.state_file,
+            mqtt_codecs: Arc::new(Vec::new()),
+            mock_broker: MockBroker::default(),
+            start_stagger: self.start_stagger,
+            host_panic_handler: None,
+            event_sink_task_handles: Vec::new(),
+            unavailable_modules: self.unavailable_modules,
+            startup_deadline,
+            max_background_tasks: self.max_background_tasks,
+            deferred_starts: Vec::new(),
+            host_extension: self.host_extension,
         })
     }
 }
 
-impl InitializedAppContext {
-    pub async fn cleanup_finished_modules(
-        &mut self,
-    ) -> anyhow::Result<Vec<Result<(), wasmtime::Trap>>> {
-        let mut results = vec![];
+fn instance_memory(module: &Module, max_memory_bytes: Option<usize>) -> usize {
+    module
+        .exports()
+        .filter_map(|export| export.ty().memory().cloned())
+        .map(|memory_type| {
+            let initial_size = (memory_type.minimum() * WASM_PAGE_SIZE) as usize;
 
-        for (_module_name, module_data) in self.modules.iter_mut() {
-            if let Some(runtime) = &mut module_data.runtime {
-                if runtime.module_task_handle.is_finished() {
-                    let runtime = module_data
-                        .runtime
-                        .take()
-                        .expect("runtime presence was checked above");
-
-                    if let Some(mqtt_event_loop_task_info) =
-                        runtime.module_mqtt_event_loop_task_info
-                    {
-                        mqtt_event_loop_task_info
-                            .runtime_event_sender
-                            .send(RuntimeEvent::RuntimeTaskStop)
-                            .await?;
-
-                        if let Err(e) = mqtt_event_loop_task_info.task_handle.await? {
-                            eprintln!("MQTT event loop task error: {}", e);
-                        }
-                    }
+            max_memory_bytes.map_or(initial_size, |max| initial_size.min(max))
+        })
+        .sum()
+}
 
-                    results.push(runtime.module_task_handle.await?);
-                }
-            }
+pub(crate) fn app_event_stream(
+    receiver: broadcast::Receiver<AppEvent>,
+) -> impl Stream<Item = AppEvent> {
+    BroadcastStream::new(receiver).map(|event| match event {
+        Ok(event) => event,
+        Err(tokio_stream::wrappers::errors::BroadcastStreamRecvError::Lagged(skipped)) => {
+            AppEvent::Lagged { skipped }
         }
+    })
+}
 
-        Ok(results)
+pub(crate) fn publish_event(app_event_sender: &broadcast::Sender<AppEvent>, event: AppEvent) {
+    // An error only means nobody is subscribed right now.
+    let _ = app_event_sender.send(event);
+}
+
+async fn epoch_supervisor_task(engine: Arc<Engine>) {
+    let mut interval = tokio::time::interval(EPOCH_TICK);
+
+    loop {
+        interval.tick().await;
+        engine.increment_epoch();
     }
+}
 
-    pub fn run_all_modules(&mut self) -> anyhow::Result<()> {
-        for (module_name, module_data) in self.modules.iter_mut() {
-            if let None = module_data.runtime {
-                let module_template = &mut module_data.module_template;
-                let mut mqtt_connection = None;
-                let mut module_mqtt_event_loop_task_info = None;
-
-                if let Some(mqtt_runtime) =
-                    initialize_mqtt_for_module(&module_template.runtime_config)
-                {
-                    match mqtt_runtime {
-                        Ok(mqtt_runtime) => {
-                            mqtt_connection = Some(mqtt_runtime.mqtt);
-
-                            let (mqtt_event_loop_runtime_sender, mqtt_event_loop_runtime_receiver) =
-                                mpsc::channel(32);
-
-                            let mqtt_event_loop_task_handle = tokio::spawn(async move {
-                                mqtt_event_loop_task(
-                                    mqtt_runtime.event_channel_sender,
-                                    mqtt_event_loop_runtime_receiver,
-                                    mqtt_runtime.event_loop,
-                                )
-                                .await
-                            });
-
-                            let mqtt_event_loop_task_info = MqttEventLoopTaskInfo {
-                                runtime_event_sender: mqtt_event_loop_runtime_sender,
-                                task_handle: mqtt_event_loop_task_handle,
-                            };
-
-                            module_mqtt_event_loop_task_info = Some(mqtt_event_loop_task_info);
-                        }
-                        Err(e) => eprintln!(
-                            "Error starting MQTT runtime for module '{}': {}",
-                            module_name, e
-                        ),
-                    }
-                }
+impl InitializedAppContext {
+    /// Subscribes to the runtime's lifecycle events. Every call gets its own stream that
+    /// sees all events published after subscribing. Publishing never waits on
+    /// subscribers: one that falls too far behind loses the oldest events and receives
+    /// an [`AppEvent::Lagged`] in their place.
+    pub fn events(&self) -> impl Stream<Item = AppEvent> {
+        app_event_stream(self.app_event_sender.subscribe())
+    }
 
-                let mut store =
-                    Store::new(&module_template.engine, WasmModuleStore { mqtt_connection });
-                let instance = module_template
-                    .linker
-                    .instantiate(&mut store, &module_template.module)?;
-                let wasm_entrypoint = instance.get_typed_func::<(), (), _>(&mut store, "start")?;
+    /// The engine every module of this context was compiled with and runs in. It is shared
+    /// by all modules, so configuration changes aren't possible through this handle, but
+    /// it can be used to compile and instantiate additional modules alongside them.
+    pub fn engine(&self) -> &Arc<Engine> {
+        &self.engine
+    }
 
-                let module_task_handle =
-                    tokio::task::spawn_blocking(move || wasm_entrypoint.call(&mut store, ()));
+    pub fn restart_stats(&self, module_name: &str) -> Option<&ModuleRestartStats> {
+        self.modules
+            .get(module_name)
+            .map(|module_data| &module_data.restart_stats)
+    }
 
-                let module_runtime = ModuleRuntime {
-                    module_task_handle,
-                    module_mqtt_event_loop_task_info,
-                };
+    fn persist_restart_stats(&self) {
+        if let Some(state_file) = &self.state_file {
+            let restart_stats = self
+                .modules
+                .iter()
+                .map(|(module_name, module_data)| {
+                    (module_name.clone(), module_data.restart_stats.clone())
+                })
+                .collect();
 
-                module_data.runtime = Some(module_runtime);
+            if let Err(e) = save_restart_stats(state_file, restart_stats) {
+                tracing::error!("Error persisting module restart statistics: {}", e);
             }
         }
+    }
 
-        Ok(())
+    /// In-memory broker of the modules with `backend = "mock"` in their MQTT config, to
+    /// inject messages and inspect what they published.
+    pub fn mock_broker(&self) -> &MockBroker {
+        &self.mock_broker
+    }
+
+    pub fn compiled_module(&self, module_name: &str) -> Option<&Module> {
+        self.modules
+            .get(module_name)
+            .map(|module_data| &module_data.module_template.module)
+    }
+
+    /// The bytes the module was compiled from: the wasm after decompression and `.wat`
+    /// assembly, or the `.cwasm` artifact of a precompiled module. The bytes are dropped
+    /// once compiled unless the module sets `retain_module_bytes`, so this is `None`
+    /// without it. Follows [`Self::replace_module_binary`].
+    pub fn module_bytes(&self, module_name: &str) -> Option<&[u8]> {
+        self.modules
+            .get(module_name)
+            .and_then(|module_data| module_data.module_bytes.as_deref())
+    }
+
+    /// Imports of the module's compiled code, empty for unknown modules.
+    pub fn module_imports(&self, module_name: &str) -> Vec<ImportInfo> {
+        self.compiled_module(module_name)
+            .map_or_else(Vec::new, |module| {
+                module
+                    .imports()
+                    .map(|import| ImportInfo {
+                        module: import.module().to_string(),
+                        name: import.name().to_string(),
+                        kind: extern_type_kind(&import.ty()),
+                        signature: extern_type_signature(&import.ty()),
+                    })
+                    .collect()
+            })
+    }
+
+    /// Exports of the module's compiled code, empty for unknown modules.
+    pub fn module_exports(&self, module_name: &str) -> Vec<ExportInfo> {
+        self.compiled_module(module_name)
+            .map_or_else(Vec::new, |module| {
+                module
+                    .exports()
+                    .map(|export| ExportInfo {
+                        name: export.name().to_string(),
+                        kind: extern_type_kind(&export.ty()),
+                        signature: extern_type_signature(&export.ty()),
+                    })
+                    .collect()
+            })
+    }
+
+    pub fn memory_usage(&self, module_name: &str) -> Option<ModuleMemoryUsage> {
+        self.modules
+            .get(module_name)
+            .map(|module_data| module_data.memory_usage)
+    }
+
+    /// Sum of the compiled size of every module plus the instance memory of every live
+    /// instance, i.e. running modules and persistent `call_export` instances. Instances
+    /// created for a single `call_export` call aren't counted.
+    pub fn total_reserved_memory(&self) -> usize {
+        self.modules
+            .values()
+            .map(|module_data| {
+                let instances = module_data.runtime.is_some() as usize
+                    + module_data.persistent_instance.is_some() as usize;
+
+                module_data.memory_usage.compiled_size
+                    + instances * module_data.memory_usage.instance_memory
+            })
+            .sum()
+    }
+
+    /// Forwards every event published from now on to `sink`, in addition to the other
+    /// sinks and [`InitializedAppContext::events`] subscribers.
+    pub fn add_event_sink(&mut self, sink: Arc<dyn EventSink>) {
+        let receiver = self.app_event_sender.subscribe();
+
+        self.event_sink_task_handles
+            .push(self.runtime_handle.spawn(event_sink_task(sink, receiver)));
+    }
+
+    /// Installs `handler` to be called when a host function panics during a module's
+    /// `start`, in addition to the module exiting with [`ModuleExit::HostError`]. Only
+    /// affects modules started afterwards.
+    pub fn set_host_panic_handler(&mut self, handler: HostPanicHandler) {
+        self.host_panic_handler = Some(handler);
+    }
+
+    /// Registers `codec` for incoming messages on topics matching `topic_filter` (MQTT
+    /// wildcards allowed). When several filters match, the codec registered first wins.
+    /// Only affects modules started afterwards.
+    pub fn register_mqtt_codec(
+        &mut self,
+        name: &str,
+        topic_filter: &str,
+        codec: Arc<dyn MqttCodec>,
+    ) {
+        Arc::make_mut(&mut self.mqtt_codecs).push(MqttCodecRegistration {
+            name: name.to_string(),
+            topic_filter: topic_filter.to_string(),
+            codec,
+        });
+    }
+
+    /// The last `n` lines the module logged through the debug API, oldest first. At most
+    /// `log_buffer_lines` are kept.
+    pub fn recent_logs(&self, module_name: &str, n: usize) -> Vec<LogRecord> {
+        self.modules
+            .get(module_name)
+            .map_or_else(Vec::new, |module_data| module_data.logs.recent(n))
+    }
+
+    /// Names of all modules, including unavailable ones.
+    pub fn module_names(&self) -> Vec<String> {
+        self.modules
+            .keys()
+            .chain(self.unavailable_modules.keys())
+            .cloned()
+            .collect()
+    }
+
+    /// Group labels mapped to the names of the modules carrying them.
+    pub fn groups(&self) -> HashMap<String, Vec<String>> {
+        let mut groups: HashMap<String, Vec<String>> = HashMap::new();
+
+        for (module_name, module_data) in self.modules.iter() {
+            if let Some(group) = &module_data.group {
+                groups
+                    .entry(group.clone())
+                    .or_default()
+                    .push(module_name.clone());
+            }
+        }
+
+        groups
+    }
+
+    /// Why the module was last started, `None` if it never was.
+    pub fn start_reason(&self, module_name: &str) -> Option<&StartReason> {
+        self.modules
+            .get(module_name)
+            .and_then(|module_data| module_data.start_reason.as_ref())
+    }
+
+    /// Time since the running module was launched, including its start delay. `None` if
+    /// it isn't running.
+    pub fn module_uptime(&self, module_name: &str) -> Option<Duration> {
+        self.modules
+            .get(module_name)?
+            .runtime
+            .as_ref()
+            .map(|runtime| runtime.control.uptime())
+    }
+
+    /// Wall-clock time the running module was launched at. `None` if it isn't running.
+    pub fn module_started_at(&self, module_name: &str) -> Option<SystemTime> {
+        self.modules
+            .get(module_name)?
+            .runtime
+            .as_ref()
+            .map(|runtime| runtime.started_at)
+    }
+
+    /// Serializable picture of every module's status, uptime, restart history, MQTT
+    /// connection and configuration. Secrets in the configuration are redacted.
+    pub fn snapshot(&self) -> RuntimeSnapshot {
+        let modules = self
+            .modules
+            .iter()
+            .map(|(module_name, module_data)| {
+                let runtime = module_data.runtime.as_ref();
+
+                let module_snapshot = ModuleSnapshot {
+                    status: self.module_status(module_name).unwrap(),
+                    group: module_data.group.clone(),
+                    started_at_ms: runtime.map(|runtime| unix_millis(runtime.started_at)),
+                    uptime_ms: runtime.map(|runtime| runtime.control.uptime().as_millis() as u64),
+                    instance_label: runtime.map(|runtime| runtime.control.instance_label()),
+                    restart_count: module_data.restart_stats.restart_count(),
+                    last_failure: module_data.restart_stats.last_failure.clone(),
+                    start_error: module_data.start_error.clone(),
+                    canary_error: module_data.canary_error.clone(),
+                    denied_host_calls: module_data.denied_host_calls.load(Ordering::Relaxed),
+                    mqtt: runtime
+                        .and_then(|runtime| runtime.mqtt_stats.as_ref())
+                        .map(|stats| MqttSnapshot::from_stats(stats)),
+                    config: module_data.module_template.runtime_config.clone(),
+                };
+
+                (module_name.clone(), module_snapshot)
+            })
+            .collect();
+
+        RuntimeSnapshot {
+            taken_at_ms: unix_millis(SystemTime::now()),
+            paused: self.pause_gate.is_paused(),
+            background_tasks: self.background_task_count(),
+            max_background_tasks: self.max_background_tasks,
+            modules,
+        }
+    }
+
+    /// Tokio tasks spawned for the running module. All of them end or are aborted when
+    /// the module is torn down, e.g. by [`InitializedAppContext::stop_module`]. Empty if
+    /// the module isn't running.
+    pub fn background_tasks(&self, module_name: &str) -> Vec<TaskInfo> {
+        self.modules
+            .get(module_name)
+            .and_then(|module_data| module_data.runtime.as_ref())
+            .map_or_else(Vec::new, |runtime| runtime.tasks.clone())
+    }
+
+    /// Number of tokio tasks of all running modules, limited by `max_background_tasks`.
+    pub fn background_task_count(&self) -> usize {
+        self.modules
+            .values()
+            .filter_map(|module_data| module_data.runtime.as_ref())
+            .map(|runtime| runtime.tasks.len())
+            .sum()
+    }
+
+    pub fn module_status(&self, module_name: &str) -> Option<ModuleStatus> {
+        if self.unavailable_modules.contains_key(module_name) {
+            return Some(ModuleStatus::Unavailable);
+        }
+
+        self.modules
+            .get(module_name)
+            .map(|module_data| match &module_data.runtime {
+                None if !module_data.enabled => ModuleStatus::Disabled,
+                None if module_data.start_error.is_some() => ModuleStatus::StartFailed,
+                None => ModuleStatus::NotStarted,
+                Some(runtime) if runtime.control.waiting_to_start() => ModuleStatus::WaitingToStart,
+                Some(runtime) if runtime.control.is_paused() => ModuleStatus::Paused,
+                Some(_) => ModuleStatus::Running,
+            })
+    }
+
+    /// Label of the module's running instance, `None` if it isn't running. Defaults to
+    /// `<module name>#<start count>` until the guest calls `set-instance-label`.
+    pub fn instance_label(&self, module_name: &str) -> Option<String> {
+        self.modules
+            .get(module_name)
+            .and_then(|module_data| module_data.runtime.as_ref())
+            .map(|runtime| runtime.control.instance_label())
+    }
+
+    /// Replaces the MQTT credentials of `module_name`, also for later starts. A running
+    /// module stays up: its connection disconnects, reconnects with `credentials` and
+    /// restores the module's subscriptions, reported as [`AppEvent::MqttReconnecting`]
+    /// followed by [`AppEvent::MqttConnected`].
+    pub fn update_mqtt_credentials(
+        &mut self,
+        module_name: &str,
+        credentials: MqttCredentials,
+    ) -> anyhow::Result<()> {
+        let module_data = self
+            .modules
+            .get_mut(module_name)
+            .ok_or_else(|| anyhow!("unknown module '{}'", module_name))?;
+
+        module_data
+            .module_template
+            .runtime_config
+            .mqtt
+            .as_mut()
+            .ok_or_else(|| anyhow!("module '{}' has no MQTT config", module_name))?
+            .set_credentials(credentials.clone());
+
+        if let Some(mqtt_event_loop_task_info) = module_data
+            .runtime
+            .as_ref()
+            .and_then(|runtime| runtime.module_mqtt_event_loop_task_info.as_ref())
+        {
+            mqtt_event_loop_task_info
+                .runtime_event_sender
+                .try_send(RuntimeEvent::UpdateCredentials(credentials))
+                .map_err(|e| {
+                    anyhow!(
+                        "can't reach the MQTT event loop of module '{}': {}",
+                        module_name,
+                        e
+                    )
+                })?;
+        }
+
+        Ok(())
+    }
+
+    /// Resolves once the running module calls `mark-ready`, right away if it already
+    /// did. Fails if it exits first or doesn't get there within `timeout`. The returned
+    /// future doesn't borrow the context.
+    pub fn wait_until_ready(
+        &self,
+        module_name: &str,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<(), ReadyError>> {
+        let readiness = self
+            .modules
+            .get(module_name)
+            .ok_or_else(|| ReadyError::UnknownModule(module_name.to_string()))
+            .and_then(|module_data| {
+                module_data
+                    .runtime
+                    .as_ref()
+                    .map(|runtime| runtime.control.readiness())
+                    .ok_or_else(|| ReadyError::NotRunning(module_name.to_string()))
+            });
+        let module_name = module_name.to_string();
+
+        async move {
+            let mut readiness = readiness?;
+            let ready = async {
+                loop {
+                    match *readiness.borrow_and_update() {
+                        Readiness::Starting => {}
+                        Readiness::Ready => return true,
+                        Readiness::Exited => return false,
+                    }
+
+                    if readiness.changed().await.is_err() {
+                        return false;
+                    }
+                }
+            };
+
+            match tokio::time::timeout(timeout, ready).await {
+                Ok(true) => Ok(()),
+                Ok(false) => Err(ReadyError::Exited(module_name)),
+                Err(_) => Err(ReadyError::Timeout {
+                    module: module_name,
+                    timeout,
+                }),
+            }
+        }
+    }
+
+    /// Makes the running module's MQTT connection unsubscribe from every topic filter the
+    /// guest subscribed to and subscribe to them again with the same QoS, e.g. after the
+    /// broker lost its subscription state. Resolves with the broker's SubAck per filter.
+    pub async fn resubscribe(&self, module_name: &str) -> anyhow::Result<Vec<SubscriptionResult>> {
+        self.request_resubscribe(module_name)?.await
+    }
+
+    /// Starts [`Self::resubscribe`]. The returned future doesn't borrow the context.
+    pub(crate) fn request_resubscribe(
+        &self,
+        module_name: &str,
+    ) -> anyhow::Result<impl Future<Output = anyhow::Result<Vec<SubscriptionResult>>>> {
+        let mqtt_event_loop_task_info = self
+            .modules
+            .get(module_name)
+            .ok_or_else(|| anyhow!("unknown module '{}'", module_name))?
+            .runtime
+            .as_ref()
+            .and_then(|runtime| runtime.module_mqtt_event_loop_task_info.as_ref())
+            .ok_or_else(|| anyhow!("module '{}' is not running with MQTT", module_name))?;
+
+        let (reply, reply_receiver) = oneshot::channel();
+
+        mqtt_event_loop_task_info
+            .runtime_event_sender
+            .try_send(RuntimeEvent::Resubscribe(reply))
+            .map_err(|e| {
+                anyhow!(
+                    "can't reach the MQTT event loop of module '{}': {}",
+                    module_name,
+                    e
+                )
+            })?;
+
+        let module_name = module_name.to_string();
+
+        Ok(async move {
+            match tokio::time::timeout(RESUBSCRIBE_TIMEOUT, reply_receiver).await {
+                Ok(Ok(result)) => result,
+                Ok(Err(_)) => Err(anyhow!(
+                    "MQTT connection of module '{}' was lost before the SubAck",
+                    module_name
+                )),
+                Err(_) => Err(anyhow!(
+                    "no SubAck for module '{}' within {:?}",
+                    module_name,
+                    RESUBSCRIBE_TIMEOUT
+                )),
+            }
+        })
+    }
+
+    /// Lets a module held by `pause_on_start` call its entrypoint.
+    pub fn continue_module(&self, module_name: &str) -> anyhow::Result<()> {
+        let module_data = self
+            .modules
+            .get(module_name)
+            .ok_or_else(|| anyhow!("unknown module '{}'", module_name))?;
+
+        match &module_data.runtime {
+            Some(runtime) if runtime.control.waiting_to_start() => {
+                runtime.control.release_start();
+                Ok(())
+            }
+            _ => Err(anyhow!("module '{}' isn't waiting to start", module_name)),
+        }
+    }
+
+    /// Suspends every running module at its next epoch tick, at most `EPOCH_TICK` from
+    /// now. Unlike stopping, the instances and their MQTT connections stay alive and pick
+    /// up where they left off on [`InitializedAppContext::resume_all`]. Modules started
+    /// while paused are suspended right away.
+    ///
+    /// A suspended guest parks the thread it runs on. All guest code, i.e. `start`,
+    /// instantiation, canaries and `call_export`, runs on the runtime's blocking pool, so
+    /// this never parks a tokio worker; a current-thread runtime keeps running too.
+    pub fn pause_all(&self) {
+        self.pause_gate.pause();
+    }
+
+    pub fn resume_all(&self) {
+        self.pause_gate.resume();
+    }
+
+    /// Calls the exported function `func_name` of `module_name` with `args` and returns its
+    /// results. The instance serving the call has no MQTT connection and is separate from
+    /// the module's running `start` instance. Guest code is subject to the module's
+    /// watchdog, memory limit and the app-wide pause, and always runs on the runtime's
+    /// blocking pool, so a slow export only holds up the calling task.
+    ///
+    /// By default every call gets a fresh instance in its own store, so no guest state
+    /// persists between calls. With `persistent_instance` set, the module is instantiated
+    /// on the first call and the store is moved to a dedicated thread from the blocking
+    /// pool that owns it from then on. Calls are sent to that thread over a channel and
+    /// executed one at a time in the order they arrive, so the guest never sees
+    /// concurrent calls. A trap discards the instance and the next call starts over with
+    /// a new one.
+    ///
+    /// With `execution_mode = "init_then_resident"` calls go to the running module's own
+    /// instance instead, which shares its store with `start` and has its MQTT connection.
+    /// They fail while the module isn't running.
+    pub async fn call_export(
+        &mut self,
+        module_name: &str,
+        func_name: &str,
+        args: &[Val],
+    ) -> anyhow::Result<Vec<Val>> {
+        let pause_gate = self.pause_gate.clone();
+        let runtime_handle = self.runtime_handle.clone();
+        let app_event_sender = self.app_event_sender.clone();
+        let host_extension = self.host_extension.clone();
+        let module_data = self
+            .modules
+            .get_mut(module_name)
+            .ok_or_else(|| anyhow!("unknown module '{}'", module_name))?;
+        let module_template = &module_data.module_template;
+        let call_span =
+            tracing::info_span!(parent: &module_data.span, "call_export", func = func_name);
+
+        if module_template.runtime_config.execution_mode == Some(ExecutionMode::InitThenResident) {
+            let call_sender = module_data
+                .runtime
+                .as_ref()
+                .and_then(|runtime| runtime.resident_call_sender.as_ref())
+                .ok_or_else(|| anyhow!("module '{}' is not running", module_name))?;
+            let (result_sender, result_receiver) = oneshot::channel();
+
+            call_sender
+                .send(ExportCall {
+                    span: call_span,
+                    func_name: func_name.to_string(),
+                    args: args.to_vec(),
+                    result_sender,
+                })
+                .map_err(|_| anyhow!("resident instance of '{}' exited", module_name))?;
+
+            return result_receiver
+                .await
+                .map_err(|_| anyhow!("resident instance of '{}' exited", module_name))?;
+        }
+
+        let instantiate_timeout = module_template
+            .runtime_config
+            .instantiate_timeout_ms
+            .map(Duration::from_millis);
+        let link_instance = || -> anyhow::Result<_> {
+            let mut store = create_store(
+                module_template,
+                None,
+                Arc::new(ModuleControl::new(
+                    pause_gate.clone(),
+                    format!("{}#call", module_name),
+                )),
+                module_data.feature_flags.clone(),
+                module_data.logs.clone(),
+                module_data.denied_host_calls.clone(),
+                ModuleLimiter::new(
+                    module_name,
+                    &module_template.runtime_config,
+                    app_event_sender.clone(),
+                ),
+            );
+            store.data_mut().extension = host_extension
+                .as_ref()
+                .map(|extension| extension.store_data(module_name));
+            let instance_pre = link(module_template, &mut store)?;
+
+            Ok((store, instance_pre))
+        };
+
+        if !module_template
+            .runtime_config
+            .persistent_instance
+            .unwrap_or(false)
+        {
+            let (mut store, instance_pre) = link_instance()?;
+            let module_name = module_name.to_string();
+            let func_name = func_name.to_string();
+            let args = args.to_vec();
+
+            return runtime_handle
+                .spawn_blocking(move || {
+                    let _call = call_span.entered();
+                    let instance =
+                        instantiate_linked(&instance_pre, &mut store, instantiate_timeout)?;
+
+                    call_func(&mut store, &instance, &module_name, &func_name, &args)
+                })
+                .await?;
+        }
+
+        let (result_sender, result_receiver) = oneshot::channel();
+        let mut call = Some(ExportCall {
+            span: call_span,
+            func_name: func_name.to_string(),
+            args: args.to_vec(),
+            result_sender,
+        });
+
+        if let Some(call_sender) = &module_data.persistent_instance {
+            // Fails if the instance was discarded after a trap.
+            call = call_sender.send(call.take().unwrap()).err().map(|e| e.0);
+        }
+
+        if let Some(call) = call {
+            let (store, instance_pre) = link_instance()?;
+            let call_sender = spawn_persistent_instance(
+                &runtime_handle,
+                module_name,
+                store,
+                instance_pre,
+                instantiate_timeout,
+            );
+
+            call_sender
+                .send(call)
+                .map_err(|_| anyhow!("persistent instance of '{}' exited", module_name))?;
+            module_data.persistent_instance = Some(call_sender);
+        }
+
+        result_receiver
+            .await
+            .map_err(|_| anyhow!("persistent instance of '{}' exited", module_name))?
+    }
+
+    /// Sets the given feature flags for `module_name`, leaving its other flags as they
+    /// are. A running guest sees the new values on its next `feature-enabled` call.
+    pub fn update_feature_flags(
+        &self,
+        module_name: &str,
+        flags: HashMap<String, bool>,
+    ) -> anyhow::Result<()> {
+        let module_data = self
+            .modules
+            .get(module_name)
+            .ok_or_else(|| anyhow!("unknown module '{}'", module_name))?;
+
+        module_data.feature_flags.write().unwrap().extend(flags);
+
+        Ok(())
+    }
+
+    fn group_members(&self, group: &str) -> Vec<String> {
+        self.modules
+            .iter()
+            .filter(|(_, module_data)| module_data.group.as_deref() == Some(group))
+            .map(|(module_name, _)| module_name.clone())
+            .collect()
+    }
+
+    /// Starts every module. Under `startup_deadline_ms` this also waits for the modules to
+    /// get through their start delay and `wait_for_mqtt` into their entrypoint, and fails
+    /// with [`InitError::StartupTimeout`] if that doesn't happen in time. Modules that
+    /// failed to start or already exited don't hold up startup.
+    async fn start_up(&mut self) -> anyhow::Result<()> {
+        self.run_all_modules()?;
+
+        let startup_deadline = match self.startup_deadline.take() {
+            Some(startup_deadline) => startup_deadline,
+            None => return Ok(()),
+        };
+
+        loop {
+            let pending_modules: Vec<String> = self
+                .modules
+                .iter()
+                .filter(|(_, module_data)| {
+                    module_data.runtime.as_ref().map_or(false, |runtime| {
+                        !runtime.control.entered() && !runtime.module_task_handle.is_finished()
+                    })
+                })
+                .map(|(module_name, _)| module_name.clone())
+                .collect();
+
+            if pending_modules.is_empty() {
+                return Ok(());
+            }
+
+            if Instant::now() >= startup_deadline {
+                return Err(InitError::StartupTimeout { pending_modules }.into());
+            }
+
+            tokio::time::sleep(CLEANUP_INTERVAL).await;
+        }
+    }
+
+    /// Starts every module and supervises them like the `cleanup_finished_modules` loop
+    /// would until Ctrl-C/SIGINT, then stops the modules that are still running. With no
+    /// modules configured this just waits for the signal.
+    pub async fn run_until_signal(&mut self) -> anyhow::Result<()> {
+        self.start_up().await?;
+
+        let mut cleanup_interval = tokio::time::interval(CLEANUP_INTERVAL);
+        let shutdown_signal = tokio::signal::ctrl_c();
+        tokio::pin!(shutdown_signal);
+
+        loop {
+            tokio::select! {
+                result = &mut shutdown_signal => {
+                    result?;
+                    break;
+                }
+                _ = cleanup_interval.tick() => {
+                    for (module_name, module_exit) in self.cleanup_finished_modules().await? {
+                        println!("Module '{}' exited: {:?}", module_name, module_exit);
+                    }
+                }
+            }
+        }
+
+        let running_module_names: Vec<String> = self
+            .modules
+            .iter()
+            .filter(|(_, module_data)| module_data.runtime.is_some())
+            .map(|(module_name, _)| module_name.clone())
+            .collect();
+
+        for module_name in running_module_names {
+            self.stop_module(&module_name).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Starts every module and waits for all of them to exit, for one-shot batch runs.
+    /// Each module is torn down, MQTT event loop included, as it finishes, and unlike
+    /// [`InitializedAppContext::cleanup_finished_modules`] restart requests are ignored.
+    /// Modules deferred by `max_background_tasks` are started as others finish. Returns
+    /// how each module exited; modules that failed to start show up in
+    /// [`InitializedAppContext::aggregate_exit_code`] only.
+    pub async fn run_to_completion(&mut self) -> anyhow::Result<Vec<(String, ModuleExit)>> {
+        self.start_up().await?;
+
+        let mut exits = vec![];
+        // Readiness of every running module, to tear each one down as soon as it exits
+        // regardless of the others.
+        let mut running = StreamMap::new();
+
+        loop {
+            for (module_name, module_data) in &self.modules {
+                if let Some(runtime) = &module_data.runtime {
+                    if !running.contains_key(module_name) {
+                        running.insert(
+                            module_name.clone(),
+                            WatchStream::new(runtime.control.readiness()),
+                        );
+                    }
+                }
+            }
+
+            let module_name = match running.next().await {
+                Some((module_name, Readiness::Exited)) => module_name,
+                Some(_) => continue,
+                None => break,
+            };
+            running.remove(&module_name);
+
+            let module_exit = self
+                .teardown_module(&module_name)
+                .await
+                .unwrap_or_else(|e| {
+                    tracing::warn!(module = %module_name, "teardown failed: {:#}", e);
+                    ModuleExit::HostError(format!("teardown failed: {:#}", e))
+                });
+
+            exits.push((module_name, module_exit));
+            self.start_deferred_modules();
+        }
+
+        if !self.deferred_starts.is_empty() {
+            tracing::warn!(
+                "modules never started, they don't fit max_background_tasks: {}",
+                self.deferred_starts.join(", ")
+            );
+        }
+
+        Ok(exits)
+    }
+
+    /// Process exit code summarizing how the modules ended, for CI and batch jobs: the
+    /// highest of the codes below over all modules, so 0 means every module that ran
+    /// finished or was stopped.
+    ///
+    /// - 0: finished or stopped
+    /// - 1: trapped
+    /// - 2: failed to start, including `require_mqtt` connect timeouts
+    /// - 3: watchdog expired
+    /// - 4: MQTT reconnect deadline expired
+    /// - 5: a host function panicked
+    /// - 6: instantiation exceeded `instantiate_timeout_ms`
+    ///
+    /// Modules that were never started and modules still running don't count.
+    pub fn aggregate_exit_code(&self) -> i32 {
+        self.modules
+            .values()
+            .map(|module_data| match module_data.start_error {
+                Some(_) => 2,
+                None => module_data.last_exit_code.unwrap_or(0),
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Tears down every module whose task has finished and returns how each exited.
+    /// Modules that asked for a restart through `request-restart` are started again
+    /// afterwards, or stopped first if they haven't returned within
+    /// `SELF_RESTART_GRACE_PERIOD`.
+    pub async fn cleanup_finished_modules(&mut self) -> anyhow::Result<Vec<(String, ModuleExit)>> {
+        for module_data in self.modules.values() {
+            if let Some(runtime) = &module_data.runtime {
+                let grace_period_over =
+                    runtime.control.restart_request().map_or(false, |request| {
+                        request.requested_at.elapsed() > SELF_RESTART_GRACE_PERIOD
+                    });
+
+                if grace_period_over && !runtime.control.stop_requested() {
+                    runtime.control.request_stop();
+                }
+            }
+        }
+
+        let finished_module_names: Vec<String> = self
+            .modules
+            .iter()
+            .filter(|(_, module_data)| {
+                module_data
+                    .runtime
+                    .as_ref()
+                    .map_or(false, |runtime| runtime.module_task_handle.is_finished())
+            })
+            .map(|(module_name, _)| module_name.clone())
+            .collect();
+
+        let mut results = vec![];
+
+        for module_name in finished_module_names {
+            let restart_request = self.modules[&module_name]
+                .runtime
+                .as_ref()
+                .and_then(|runtime| runtime.control.restart_request());
+            let module_exit = self.teardown_module(&module_name).await?;
+
+            if let Some(restart_request) = restart_request {
+                self.self_restart_module(&module_name, restart_request.reason);
+            }
+
+            results.push((module_name, module_exit));
+        }
+
+        self.start_deferred_modules();
+
+        Ok(results)
+    }
+
+    /// Starts deferred modules in order for as long as they fit under
+    /// `max_background_tasks`.
+    fn start_deferred_modules(&mut self) {
+        let mut deferred_starts = std::mem::take(&mut self.deferred_starts).into_iter();
+
+        for module_name in deferred_starts.by_ref() {
+            if self.task_limit_reached(&module_name).is_some() {
+                self.deferred_starts.push(module_name);
+                break;
+            }
+
+            if let Err(e) = self.run_module_after(&module_name, Duration::ZERO) {
+                tracing::error!("{:#}", e);
+            }
+        }
+
+        self.deferred_starts.extend(deferred_starts);
+    }
+
+    /// Number of running tasks if starting the module could exceed `max_background_tasks`.
+    fn task_limit_reached(&self, module_name: &str) -> Option<usize> {
+        let max_background_tasks = self.max_background_tasks?;
+        let module_data = self.modules.get(module_name)?;
+        let running = self.background_task_count();
+
+        if module_data.runtime.is_none()
+            && running + module_data.module_template.runtime_config.max_task_count()
+                > max_background_tasks
+        {
+            Some(running)
+        } else {
+            None
+        }
+    }
+
+    fn self_restart_module(&mut self, module_name: &str, reason: String) {
+        let module_data = match self.modules.get_mut(module_name) {
+            Some(module_data) => module_data,
+            None => return,
+        };
+
+        if let Some(last_self_restart) = module_data.last_self_restart {
+            if last_self_restart.elapsed() < SELF_RESTART_MIN_INTERVAL {
+                tracing::warn!(
+                    "Module '{}' requested a restart ({}) within {:?} of the last one, not restarting it",
+                    module_name, reason, SELF_RESTART_MIN_INTERVAL
+                );
+                return;
+            }
+        }
+
+        module_data.last_self_restart = Some(Instant::now());
+        module_data.pending_self_restart = Some(reason);
+
+        if let Err(e) = self.run_module(module_name) {
+            tracing::error!("{:#}", e);
+        }
+    }
+
+    /// Starts every module that isn't running yet. A module that fails to start doesn't
+    /// keep the others from starting; its failure is reported through
+    /// [`AppEvent::ModuleStartFailed`] and [`ModuleStatus::StartFailed`].
+    pub fn run_all_modules(&mut self) -> anyhow::Result<()> {
+        let module_names: Vec<String> = self.modules.keys().cloned().collect();
+
+        self.run_modules(module_names);
+
+        Ok(())
+    }
+
+    pub fn run_group(&mut self, group: &str) -> anyhow::Result<()> {
+        let module_names = self.group_members(group);
+
+        self.run_modules(module_names);
+
+        Ok(())
+    }
+
+    /// Starts `module_names` one after another, spacing consecutive launches
+    /// `start_stagger_ms` apart. The delays are slept in the spawned module tasks, so this
+    /// returns right away.
+    fn run_modules(&mut self, module_names: Vec<String>) {
+        let mut stagger_delay = Duration::ZERO;
+
+        for module_name in module_names {
+            let skip = self.modules.get(&module_name).map_or(false, |module_data| {
+                module_data.runtime.is_some() || !module_data.enabled
+            });
+
+            if skip {
+                continue;
+            }
+
+            if let Err(e) = self.run_module_after(&module_name, stagger_delay) {
+                tracing::error!("{:#}", e);
+            }
+
+            stagger_delay += self.start_stagger;
+        }
+    }
+
+    pub async fn stop_group(&mut self, group: &str) -> anyhow::Result<Vec<(String, ModuleExit)>> {
+        let mut results = vec![];
+
+        for module_name in self.group_members(group) {
+            if let Some(module_exit) = self.stop_module(&module_name).await? {
+                results.push((module_name, module_exit));
+            }
+        }
+
+        Ok(results)
+    }
+
+    /// Starts the module unless it is already running. Fails for disabled modules.
+    pub fn run_module(&mut self, module_name: &str) -> anyhow::Result<()> {
+        self.check_startable(module_name)?;
+
+        self.run_module_after(module_name, Duration::ZERO)
+    }
+
+    fn check_startable(&self, module_name: &str) -> anyhow::Result<()> {
+        if let Some(error) = self.unavailable_modules.get(module_name) {
+            return Err(anyhow!(
+                "module '{}' is unavailable: {}",
+                module_name,
+                error
+            ));
+        }
+
+        if self
+            .modules
+            .get(module_name)
+            .map_or(false, |module_data| !module_data.enabled)
+        {
+            return Err(anyhow!("module '{}' is disabled", module_name));
+        }
+
+        Ok(())
+    }
+
+    /// Tries the module out before starting it with [`Self::run_module`]. The canary is a
+    /// fresh instance in its own store without MQTT that calls the module's `self_test`
+    /// export, or `start` if it has none, and is stopped once `budget`, which includes
+    /// instantiation, is used up.
+    /// `self_test` has to return within the budget. `start` usually doesn't return, so it
+    /// passes unless it traps or hits the watchdog first.
+    ///
+    /// If the canary fails the module stays [`ModuleStatus::NotStarted`] and the error is
+    /// kept, see [`Self::canary_error`] and [`AppEvent::ModuleCanaryFailed`].
+    pub async fn canary_start(
+        &mut self,
+        module_name: &str,
+        budget: Duration,
+    ) -> anyhow::Result<()> {
+        self.check_startable(module_name)?;
+
+        let module_data = self
+            .modules
+            .get(module_name)
+            .ok_or_else(|| anyhow!("unknown module '{}'", module_name))?;
+
+        if module_data.runtime.is_some() {
+            return Err(anyhow!("module '{}' is already running", module_name));
+        }
+
+        let result = match self.prepare_canary(module_name) {
+            Ok((mut store, instance_pre, instantiate_timeout)) => {
+                let control = store.data().control.clone();
+                let budget_control = control.clone();
+                let budget_task_handle = self.runtime_handle.spawn(async move {
+                    tokio::time::sleep(budget).await;
+                    budget_control.request_stop();
+                });
+
+                let result = self
+                    .runtime_handle
+                    .spawn_blocking(move || {
+                        let instance =
+                            instantiate_linked(&instance_pre, &mut store, instantiate_timeout)
+                                .context("instantiate")?;
+                        let self_test = instance.get_func(&mut store, "self_test").is_some();
+                        let entrypoint_name = if self_test { "self_test" } else { "start" };
+                        let entrypoint = instance
+                            .get_typed_func::<(), (), _>(&mut store, entrypoint_name)
+                            .with_context(|| format!("look up entrypoint `{}`", entrypoint_name))?;
+
+                        anyhow::Ok((entrypoint.call(&mut store, ()), self_test))
+                    })
+                    .await;
+                budget_task_handle.abort();
+
+                match result.map_err(JoinError::try_into_panic) {
+                    Ok(Err(e)) => Err(e),
+                    Ok(Ok((result, self_test))) => match module_exit(result, &control) {
+                        ModuleExit::Finished => Ok(()),
+                        ModuleExit::Stopped if !self_test => Ok(()),
+                        ModuleExit::Stopped => {
+                            Err(anyhow!("`self_test` didn't return within {:?}", budget))
+                        }
+                        ModuleExit::Trapped(trap) => Err(anyhow!(trap).context("trapped")),
+                        ModuleExit::HostError(message) => {
+                            Err(anyhow!("host function panicked: {}", message))
+                        }
+                        module_exit => Err(anyhow!("exited with {:?}", module_exit)),
+                    },
+                    Err(Ok(panic)) => {
+                        Err(anyhow!("host function panicked: {}", panic_message(panic)))
+                    }
+                    Err(Err(e)) => Err(anyhow!(e)),
+                }
+            }
+            Err(e) => Err(e),
+        };
+
+        let module_data = self
+            .modules
+            .get_mut(module_name)
+            .ok_or_else(|| anyhow!("unknown module '{}'", module_name))?;
+
+        match result {
+            Ok(()) => {
+                module_data.canary_error = None;
+
+                self.run_module(module_name)
+            }
+            Err(e) => {
+                let error = format!("{:#}", e);
+
+                tracing::warn!("Canary of module '{}' failed: {}", module_name, error);
+                module_data.canary_error = Some(error.clone());
+
+                publish_event(
+                    &self.app_event_sender,
+                    AppEvent::ModuleCanaryFailed {
+                        name: module_name.to_string(),
+                        error,
+                    },
+                );
+
+                Err(e.context(format!("canary of module '{}' failed", module_name)))
+            }
+        }
+    }
+
+    /// The canary's store, the module linked for it and its `instantiate_timeout_ms`.
+    fn prepare_canary(
+        &self,
+        module_name: &str,
+    ) -> anyhow::Result<(
+        Store<WasmModuleStore>,
+        InstancePre<WasmModuleStore>,
+        Option<Duration>,
+    )> {
+        let module_data = self
+            .modules
+            .get(module_name)
+            .ok_or_else(|| anyhow!("unknown module '{}'", module_name))?;
+        let module_template = &module_data.module_template;
+
+        let mut store = create_store(
+            module_template,
+            None,
+            Arc::new(ModuleControl::new(
+                self.pause_gate.clone(),
+                format!("{}#canary", module_name),
+            )),
+            module_data.feature_flags.clone(),
+            module_data.logs.clone(),
+            module_data.denied_host_calls.clone(),
+            ModuleLimiter::new(
+                module_name,
+                &module_template.runtime_config,
+                self.app_event_sender.clone(),
+            ),
+        );
+        store.data_mut().extension = self
+            .host_extension
+            .as_ref()
+            .map(|extension| extension.store_data(module_name));
+        let instance_pre = link(module_template, &mut store).context("instantiate")?;
+        let instantiate_timeout = module_template
+            .runtime_config
+            .instantiate_timeout_ms
+            .map(Duration::from_millis);
+
+        Ok((store, instance_pre, instantiate_timeout))
+    }
+
+    /// Why the module's last [`Self::canary_start`] failed, `None` if it passed or never
+    /// ran.
+    pub fn canary_error(&self, module_name: &str) -> Option<&str> {
+        self.modules
+            .get(module_name)
+            .and_then(|module_data| module_data.canary_error.as_deref())
+    }
+
+    fn run_module_after(
+        &mut self,
+        module_name: &str,
+        stagger_delay: Duration,
+    ) -> anyhow::Result<()> {
+        if let Some(running) = self.task_limit_reached(module_name) {
+            tracing::warn!(
+                "Starting module '{}' could exceed max_background_tasks ({} running), \
+                 deferring it",
+                module_name,
+                running
+            );
+
+            if !self.deferred_starts.iter().any(|name| name == module_name) {
+                self.deferred_starts.push(module_name.to_string());
+            }
+
+            publish_event(
+                &self.app_event_sender,
+                AppEvent::TaskLimitReached {
+                    name: module_name.to_string(),
+                    running,
+                    limit: self.max_background_tasks.unwrap_or_default(),
+                },
+            );
+
+            return Ok(());
+        }
+
+        let result = self.start_module(module_name, stagger_delay);
+
+        if let Some(module_data) = self.modules.get_mut(module_name) {
+            match &result {
+                Ok(()) => {
+                    module_data.start_error = None;
+                    module_data.restart_stats.start_count += 1;
+                }
+                Err(e) => {
+                    // The whole cause chain, e.g. "instantiate: incompatible import ...".
+                    let error = format!("{:#}", e);
+
+                    module_data.start_error = Some(error.clone());
+                    module_data.restart_stats.last_failure = Some(error.clone());
+
+                    publish_event(
+                        &self.app_event_sender,
+                        AppEvent::ModuleStartFailed {
+                            name: module_name.to_string(),
+                            error,
+                        },
+                    );
+                }
+            }
+        }
+
+        self.persist_restart_stats();
+
+        result.with_context(|| format!("module '{}' failed to start", module_name))
+    }
+
+    fn start_module(&mut self, module_name: &str, stagger_delay: Duration) -> anyhow::Result<()> {
+        let module_data = self
+            .modules
+            .get_mut(module_name)
+            .ok_or_else(|| anyhow!("unknown module '{}'", module_name))?;
+
+        if module_data.runtime.is_some() {
+            return Ok(());
+        }
+
+        let module_template = &mut module_data.module_template;
+        let control = Arc::new(ModuleControl::new(
+            self.pause_gate.clone(),
+            format!("{}#{}", module_name, module_data.restart_stats.start_count),
+        ));
+        let start_span = tracing::info_span!(
+            parent: &module_data.span,
+            "start",
+            instance = %control.instance_label()
+        );
+        let start_delay = stagger_delay
+            + module_template
+                .runtime_config
+                .start_delay_ms
+                .map_or(Duration::ZERO, Duration::from_millis);
+        let mut store = create_store(
+            module_template,
+            None,
+            control.clone(),
+            module_data.feature_flags.clone(),
+            module_data.logs.clone(),
+            module_data.denied_host_calls.clone(),
+            ModuleLimiter::new(
+                module_name,
+                &module_template.runtime_config,
+                self.app_event_sender.clone(),
+            ),
+        );
+        store.data_mut().extension = self
+            .host_extension
+            .as_ref()
+            .map(|extension| extension.store_data(module_name));
+        // Everything that can fail without running guest code is checked before any task
+        // is spawned for the module. Instantiation itself runs in the module task.
+        let instance_pre = link(module_template, &mut store).context("instantiate")?;
+        check_entrypoint(&module_template.module).context("look up entrypoint `start`")?;
+
+        let mut mqtt_connection = None;
+        let mut mqtt_connected = None;
+        let mut mqtt_stats = None;
+        let mut mqtt_subscriptions = None;
+        let mut module_mqtt_event_loop_task_info = None;
+        let mut tasks = vec![];
+        let mut auxiliary_task_handles = vec![];
+
+        if let Some(mqtt_runtime) = initialize_mqtt_for_module(
+            &module_template.runtime_config,
+            control.shutdown_signal(),
+            &self.mock_broker,
+        ) {
+            match mqtt_runtime {
+                Ok(mut mqtt_runtime) => {
+                    if let Some(subscriptions) = module_data.carried_subscriptions.take() {
+                        mqtt_runtime.restore_subscriptions(subscriptions);
+                    }
+
+                    mqtt_subscriptions = Some(mqtt_runtime.mqtt.subscriptions());
+                    mqtt_connection = Some(mqtt_runtime.mqtt);
+                    mqtt_connected = Some(mqtt_runtime.stats.connected.subscribe());
+                    mqtt_stats = Some(mqtt_runtime.stats.clone());
+
+                    let runtime_config = &module_template.runtime_config;
+                    let connect_deadline = runtime_config
+                        .mqtt_connect_deadline_ms
+                        .map(Duration::from_millis);
+                    let reconnect_deadline = runtime_config
+                        .mqtt_reconnect_deadline_ms
+                        .map(Duration::from_millis);
+
+                    if connect_deadline.is_some() || reconnect_deadline.is_some() {
+                        let mqtt_deadline_task = mqtt_deadline_task(
+                            module_name.to_string(),
+                            self.app_event_sender.clone(),
+                            control.clone(),
+                            mqtt_runtime.stats.connected.subscribe(),
+                            connect_deadline,
+                            reconnect_deadline,
+                            runtime_config.require_mqtt.unwrap_or(false),
+                        );
+
+                        auxiliary_task_handles.push(self.runtime_handle.spawn(async move {
+                            tokio::time::sleep(start_delay).await;
+                            mqtt_deadline_task.await
+                        }));
+                        tasks.push(TaskInfo {
+                            kind: TaskKind::MqttDeadline,
+                            spawned_at: SystemTime::now(),
+                        });
+                    }
+
+                    let (mqtt_event_loop_runtime_sender, mqtt_event_loop_runtime_receiver) =
+                        mpsc::channel(mqtt_runtime.command_channel_capacity);
+
+                    let module_name = module_name.to_string();
+                    let app_event_sender = self.app_event_sender.clone();
+                    let mqtt_codecs = self.mqtt_codecs.clone();
+                    let event_channel_sender = mqtt_runtime.event_channel_sender.clone();
+
+                    if let Some(publish_batch_task) = mqtt_runtime.publish_batch_task {
+                        auxiliary_task_handles
+                            .push(self.runtime_handle.spawn(publish_batch_task.run()));
+                        tasks.push(TaskInfo {
+                            kind: TaskKind::MqttPublishBatch,
+                            spawned_at: SystemTime::now(),
+                        });
+                    }
+
+                    let mqtt_event_loop_span =
+                        tracing::info_span!(parent: &start_span, "mqtt_event_loop");
+                    let event_loop_task = async move {
+                        tokio::time::sleep(start_delay).await;
+
+                        mqtt_event_loop_task(
+                            module_name,
+                            app_event_sender,
+                            mqtt_runtime.stats,
+                            mqtt_codecs,
+                            mqtt_runtime.event_channel_sender,
+                            mqtt_event_loop_runtime_receiver,
+                            mqtt_runtime.event_loop,
+                        )
+                        .await
+                    };
+                    let mqtt_event_loop_task_handle = self
+                        .runtime_handle
+                        .spawn(event_loop_task.instrument(mqtt_event_loop_span));
+
+                    let mqtt_event_loop_task_info = MqttEventLoopTaskInfo {
+                        runtime_event_sender: mqtt_event_loop_runtime_sender,
+                        event_channel_sender,
+                        task_handle: mqtt_event_loop_task_handle,
+                    };
+
+                    module_mqtt_event_loop_task_info = Some(mqtt_event_loop_task_info);
+                    tasks.push(TaskInfo {
+                        kind: TaskKind::MqttEventLoop,
+                        spawned_at: SystemTime::now(),
+                    });
+                }
+                Err(e) if module_template.runtime_config.require_mqtt.unwrap_or(false) => {
+                    return Err(e.context("MQTT is required but its runtime failed to start"));
+                }
+                Err(e) => {
+                    eprintln!(
+                        "Error starting MQTT runtime for module '{}': {}",
+                        module_name, e
+                    );
+
+                    publish_event(
+                        &self.app_event_sender,
+                        AppEvent::MqttDegraded {
+                            name: module_name.to_string(),
+                            error: e.to_string(),
+                        },
+                    );
+                }
+            }
+        }
+
+        store.data_mut().mqtt_connection = mqtt_connection;
+
+        let runtime_config = &module_template.runtime_config;
+        let instantiate_timeout = runtime_config
+            .instantiate_timeout_ms
+            .map(Duration::from_millis);
+        let wait_for_mqtt = runtime_config.wait_for_mqtt.unwrap_or(false);
+        let wait_for_mqtt_timeout = runtime_config
+            .wait_for_mqtt_timeout_ms
+            .map_or(DEFAULT_WAIT_FOR_MQTT_TIMEOUT, Duration::from_millis);
+        let require_mqtt = runtime_config.require_mqtt.unwrap_or(false);
+        let pause_on_start = runtime_config.pause_on_start.unwrap_or(false);
+        let pause_on_start_timeout = runtime_config
+            .pause_on_start_timeout_ms
+            .map_or(DEFAULT_PAUSE_ON_START_TIMEOUT, Duration::from_millis);
+
+        let (resident_call_sender, resident_call_receiver) = match runtime_config.execution_mode {
+            Some(ExecutionMode::InitThenResident) => {
+                let (call_sender, call_receiver) = std::sync::mpsc::channel();
+                (Some(call_sender), Some(call_receiver))
+            }
+            Some(ExecutionMode::Resident) | None => (None, None),
+        };
+
+        let cgroup_config = runtime_config.cgroup.clone();
+        let task_module_name = module_name.to_string();
+        let task_control = control.clone();
+        let task_runtime_handle = self.runtime_handle.clone();
+        let task_host_panic_handler = self.host_panic_handler.clone();
+
+        let module_task = async move {
+            tokio::time::sleep(start_delay).await;
+
+            if task_control.stop_requested() {
+                // The store owns the MQTT connection's runtime, which must not be dropped
+                // from async context.
+                task_runtime_handle.spawn_blocking(move || drop(store));
+
+                return ModuleExit::Stopped;
+            }
+
+            if let (true, Some(mqtt_connected)) = (wait_for_mqtt, mqtt_connected) {
+                if !wait_for_mqtt_connection(mqtt_connected, wait_for_mqtt_timeout).await {
+                    if require_mqtt {
+                        // The store owns the MQTT connection's runtime, which must not be
+                        // dropped from async context.
+                        task_runtime_handle.spawn_blocking(move || drop(store));
+
+                        return ModuleExit::MqttConnectTimeout;
+                    }
+
+                    tracing::warn!(
+                        "MQTT for module '{}' not connected after {:?}, starting it anyway",
+                        task_module_name,
+                        wait_for_mqtt_timeout
+                    );
+                }
+            }
+
+            if pause_on_start {
+                tracing::info!(
+                    "Module '{}' waiting to start, continue it within {:?}",
+                    task_module_name,
+                    pause_on_start_timeout
+                );
+
+                if !task_control.hold_start(pause_on_start_timeout).await {
+                    if task_control.stop_requested() {
+                        // The store owns the MQTT connection's runtime, which must not be
+                        // dropped from async context.
+                        task_runtime_handle.spawn_blocking(move || drop(store));
+
+                        return ModuleExit::Stopped;
+                    }
+
+                    tracing::warn!(
+                        "Module '{}' not continued after {:?}, starting it anyway",
+                        task_module_name,
+                        pause_on_start_timeout
+                    );
+                }
+            }
+
+            task_control.mark_entered();
+
+            let resident_module_name = task_module_name.clone();
+            // Spans don't propagate to the blocking pool on their own.
+            let entrypoint_span = tracing::info_span!("entrypoint");
+            let result = task_runtime_handle
+                .spawn_blocking(move || {
+                    let _entrypoint = entrypoint_span.entered();
+                    let _cgroup = cgroup_config.as_ref().and_then(|cgroup_config| {
+                        cgroup::enter(&resident_module_name, cgroup_config)
+                    });
+                    let instance =
+                        instantiate_linked(&instance_pre, &mut store, instantiate_timeout)
+                            .map_err(instantiate_trap)?;
+                    let wasm_entrypoint = instance
+                        .get_typed_func::<(), (), _>(&mut store, "start")
+                        .map_err(instantiate_trap)?;
+                    let result = wasm_entrypoint.call(&mut store, ());
+
+                    match resident_call_receiver {
+                        Some(call_receiver) if result.is_ok() => serve_resident_calls(
+                            &mut store,
+                            &instance,
+                            &resident_module_name,
+                            call_receiver,
+                        ),
+                        _ => result,
+                    }
+                })
+                .await;
+
+            match result.map_err(JoinError::try_into_panic) {
+                Ok(result) => module_exit(result, &task_control),
+                Err(Ok(panic)) => {
+                    let message = panic_message(panic);
+
+                    if let Some(host_panic_handler) = task_host_panic_handler {
+                        host_panic_handler(&task_module_name, &message);
+                    }
+
+                    ModuleExit::HostError(message)
+                }
+                // The blocking task was cancelled because the runtime is shutting down.
+                Err(Err(_)) => ModuleExit::Stopped,
+            }
+        };
+        let exit_control = control.clone();
+        let module_task_handle = self.runtime_handle.spawn(
+            async move {
+                let module_exit = module_task.await;
+                exit_control.mark_exited();
+                module_exit
+            }
+            .instrument(start_span),
+        );
+        tasks.push(TaskInfo {
+            kind: TaskKind::Entrypoint,
+            spawned_at: SystemTime::now(),
+        });
+
+        let module_runtime = ModuleRuntime {
+            module_task_handle,
+            module_mqtt_event_loop_task_info,
+            control,
+            mqtt_stats,
+            started_at: SystemTime::now(),
+            tasks,
+            auxiliary_task_handles,
+            resident_call_sender,
+            mqtt_subscriptions,
+        };
+
+        module_data.runtime = Some(module_runtime);
+
+        let start_reason = match module_data.pending_self_restart.take() {
+            Some(reason) => StartReason::Restart {
+                self_requested: true,
+                reason: Some(reason),
+            },
+            None if module_data.start_reason.is_some() => StartReason::Restart {
+                self_requested: false,
+                reason: None,
+            },
+            None => StartReason::Initial,
+        };
+        module_data.start_reason = Some(start_reason.clone());
+
+        publish_event(
+            &self.app_event_sender,
+            AppEvent::ModuleStarted {
+                name: module_name.to_string(),
+                reason: start_reason,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Swaps the binary of `module_name` for `new_bytes`, e.g. to roll out a new version.
+    /// The new binary is compiled first, so if that fails the module keeps running the old
+    /// one. A running module is then stopped like with [`Self::stop_module`] and started
+    /// again with the new binary, whose MQTT connection restores the subscriptions of the
+    /// old one. A module that isn't running uses the new binary on its next start.
+    pub async fn replace_module_binary(
+        &mut self,
+        module_name: &str,
+        new_bytes: &[u8],
+    ) -> anyhow::Result<()> {
+        let module_data = self
+            .modules
+            .get(module_name)
+            .ok_or_else(|| anyhow!("unknown module '{}'", module_name))?;
+
+        let module = Module::from_binary(&self.engine, new_bytes)
+            .with_context(|| format!("compile new binary of module '{}'", module_name))?;
+        let memory_usage = ModuleMemoryUsage {
+            compiled_size: module.image_range().len(),
+            instance_memory: instance_memory(
+                &module,
+                module_data.module_template.runtime_config.max_memory_bytes,
+            ),
+        };
+        // `Some` if the module is running, holding its subscriptions if it has MQTT.
+        let running_subscriptions = module_data.runtime.as_ref().map(|runtime| {
+            runtime
+                .mqtt_subscriptions
+                .as_ref()
+                .map(|subscriptions| subscriptions.lock().unwrap().clone())
+        });
+
+        if running_subscriptions.is_some() {
+            self.stop_module(module_name).await?;
+        }
+
+        let module_data = self
+            .modules
+            .get_mut(module_name)
+            .ok_or_else(|| anyhow!("unknown module '{}'", module_name))?;
+
+        module_data.module_template.module = module;
+        module_data.memory_usage = memory_usage;
+        if module_data.module_bytes.is_some() {
+            module_data.module_bytes = Some(new_bytes.into());
+        }
+        // Still runs the old binary.
+        module_data.persistent_instance = None;
+
+        publish_event(
+            &self.app_event_sender,
+            AppEvent::ModuleReplaced {
+                name: module_name.to_string(),
+            },
+        );
+
+        if let Some(subscriptions) = running_subscriptions {
+            module_data.carried_subscriptions = subscriptions;
+            self.run_module(module_name)?;
+        }
+
+        Ok(())
+    }
+
+    /// Interrupts a running module at its next epoch tick and tears down its runtime.
+    /// Returns `None` if the module wasn't running.
+    pub async fn stop_module(&mut self, module_name: &str) -> anyhow::Result<Option<ModuleExit>> {
+        let module_data = self
+            .modules
+            .get(module_name)
+            .ok_or_else(|| anyhow!("unknown module '{}'", module_name))?;
+
+        self.deferred_starts.retain(|name| name != module_name);
+
+        match &module_data.runtime {
+            Some(runtime) => runtime.control.request_stop(),
+            None => return Ok(None),
+        }
+
+        Ok(Some(self.teardown_module(module_name).await?))
+    }
+
+    pub(crate) fn subscribe_events(&self) -> broadcast::Receiver<AppEvent> {
+        self.app_event_sender.subscribe()
+    }
+
+    pub(crate) fn event_sender(&self) -> &broadcast::Sender<AppEvent> {
+        &self.app_event_sender
+    }
+
+    pub(crate) fn runtime_handle(&self) -> &Handle {
+        &self.runtime_handle
+    }
+
+    /// Delivers `event` to the module's MQTT event channel as if it came from the broker.
+    #[cfg(feature = "test-util")]
+    pub(crate) async fn inject_mqtt_event(
+        &self,
+        module_name: &str,
+        event: rumqttc::Event,
+    ) -> anyhow::Result<()> {
+        let event_channel_sender = self
+            .modules
+            .get(module_name)
+            .and_then(|module_data| module_data.runtime.as_ref())
+            .and_then(|runtime| runtime.module_mqtt_event_loop_task_info.as_ref())
+            .map(|mqtt_event_loop_task_info| &mqtt_event_loop_task_info.event_channel_sender)
+            .ok_or_else(|| anyhow!("module '{}' is not running with MQTT", module_name))?;
+
+        event_channel_sender
+            .send(event)
+            .await
+            .map_err(|e| anyhow!("Error injecting MQTT event: {}", e))
+    }
+
+    /// Waits for the running module to exit on its own and tears down its runtime.
+    pub(crate) async fn teardown_module(
+        &mut self,
+        module_name: &str,
+    ) -> anyhow::Result<ModuleExit> {
+        let runtime = self
+            .modules
+            .get_mut(module_name)
+            .and_then(|module_data| module_data.runtime.take())
+            .ok_or_else(|| anyhow!("module '{}' is not running", module_name))?;
+
+        // Aborted however the rest of the teardown goes, even if this future is dropped.
+        let _auxiliary_tasks = AbortOnDrop(runtime.auxiliary_task_handles);
+
+        let module_exit = match runtime.module_task_handle.await {
+            Ok(module_exit) => module_exit,
+            Err(e) => match e.try_into_panic() {
+                Ok(panic) => ModuleExit::HostError(panic_message(panic)),
+                // Aborted, e.g. because the runtime is shutting down.
+                Err(_) => ModuleExit::Stopped,
+            },
+        };
+
+        if let Some(mqtt_event_loop_task_info) = runtime.module_mqtt_event_loop_task_info {
+            // The event loop may have exited already, e.g. on a connection error, so
+            // failing to stop it doesn't fail the teardown.
+            if mqtt_event_loop_task_info
+                .runtime_event_sender
+                .send(RuntimeEvent::RuntimeTaskStop)
+                .await
+                .is_err()
+            {
+                tracing::warn!(module = module_name, "MQTT event loop already stopped");
+            }
+
+            match mqtt_event_loop_task_info.task_handle.await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    tracing::warn!(module = module_name, "MQTT event loop task error: {:#}", e)
+                }
+                Err(e) => {
+                    tracing::warn!(module = module_name, "MQTT event loop task failed: {}", e)
+                }
+            }
+        }
+
+        let failure = match &module_exit {
+            ModuleExit::Trapped(trap) => Some(trap.to_string()),
+            ModuleExit::Watchdog => Some("watchdog expired".to_string()),
+            ModuleExit::MqttConnectTimeout => Some("MQTT connection timed out".to_string()),
+            ModuleExit::MqttReconnectTimeout => Some("MQTT reconnect timed out".to_string()),
+            ModuleExit::HostError(error) => Some(format!("host function panicked: {}", error)),
+            ModuleExit::InstantiateTimeout => Some("instantiation timed out".to_string()),
+            ModuleExit::Finished | ModuleExit::Stopped => None,
+        };
+
+        if let Some(module_data) = self.modules.get_mut(module_name) {
+            module_data.last_exit_code = Some(module_exit.exit_code());
+        }
+
+        if let Some(failure) = failure {
+            if let Some(module_data) = self.modules.get_mut(module_name) {
+                module_data.restart_stats.last_failure = Some(failure);
+            }
+
+            self.persist_restart_stats();
+        }
+
+        publish_event(
+            &self.app_event_sender,
+            module_exit_event(module_name, &module_exit),
+        );
+
+        Ok(module_exit)
+    }
+}
+
+/// Aborts the tasks when dropped.
+struct AbortOnDrop(Vec<tokio::task::JoinHandle<()>>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        for task_handle in &self.0 {
+            task_handle.abort();
+        }
+    }
+}
+
+fn call_func(
+    store: &mut Store<WasmModuleStore>,
+    instance: &Instance,
+    module_name: &str,
+    func_name: &str,
+    args: &[Val],
+) -> anyhow::Result<Vec<Val>> {
+    let func = instance.get_func(&mut *store, func_name).ok_or_else(|| {
+        anyhow!(
+            "module '{}' has no exported function '{}'",
+            module_name,
+            func_name
+        )
+    })?;
+
+    let func_type = func.ty(&*store);
+    let arg_types: Vec<ValType> = args.iter().map(Val::ty).collect();
+    if !func_type.params().eq(arg_types.iter().cloned()) {
+        return Err(anyhow!(
+            "argument mismatch calling '{}' of module '{}': expected {:?}, got {:?}",
+            func_name,
+            module_name,
+            func_type.params().collect::<Vec<_>>(),
+            arg_types
+        ));
+    }
+
+    // Time between calls doesn't count against the watchdog.
+    store.data().control.touch();
+
+    let mut results: Vec<Val> = func_type.results().map(default_val).collect();
+    func.call(&mut *store, args, &mut results)?;
+
+    Ok(results)
+}
+
+/// Moves `store` to a thread of the blocking pool that serves calls until the returned
+/// sender is dropped or a call traps.
+fn spawn_persistent_instance(
+    runtime_handle: &Handle,
+    module_name: &str,
+    mut store: Store<WasmModuleStore>,
+    instance_pre: InstancePre<WasmModuleStore>,
+    instantiate_timeout: Option<Duration>,
+) -> std::sync::mpsc::Sender<ExportCall> {
+    let (call_sender, call_receiver) = std::sync::mpsc::channel::<ExportCall>();
+    let module_name = module_name.to_string();
+
+    runtime_handle.spawn_blocking(move || {
+        let instance = match instantiate_linked(&instance_pre, &mut store, instantiate_timeout) {
+            Ok(instance) => instance,
+            Err(e) => {
+                // Fails the call that wanted the instance; the next one starts over.
+                if let Ok(call) = call_receiver.recv() {
+                    let _ = call.result_sender.send(Err(e));
+                }
+
+                return;
+            }
+        };
+
+        for call in call_receiver {
+            let _call = call.span.enter();
+            let result = call_func(
+                &mut store,
+                &instance,
+                &module_name,
+                &call.func_name,
+                &call.args,
+            );
+            let trapped = matches!(&result, Err(e) if e.is::<wasmtime::Trap>());
+
+            let _ = call.result_sender.send(result);
+
+            if trapped {
+                tracing::warn!(
+                    "Persistent instance of module '{}' trapped, discarding it",
+                    module_name
+                );
+                break;
+            }
+        }
+    });
+
+    call_sender
+}
+
+/// Serves `call_export` calls against the instance of an `init_then_resident` module
+/// after its `start` returned, until the module is stopped or a call traps.
+fn serve_resident_calls(
+    store: &mut Store<WasmModuleStore>,
+    instance: &Instance,
+    module_name: &str,
+    call_receiver: std::sync::mpsc::Receiver<ExportCall>,
+) -> Result<(), wasmtime::Trap> {
+    loop {
+        let call = match call_receiver.recv_timeout(RESIDENT_STOP_POLL_INTERVAL) {
+            Ok(call) => call,
+            Err(RecvTimeoutError::Timeout) if store.data().control.stop_requested() => {
+                return Err(wasmtime::Trap::new("module stop requested"));
+            }
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+
+        let _call = call.span.enter();
+        let result = call_func(store, instance, module_name, &call.func_name, &call.args);
+        let trap = result
+            .as_ref()
+            .err()
+            .and_then(|e| e.downcast_ref::<wasmtime::Trap>())
+            .cloned();
+
+        let _ = call.result_sender.send(result);
+
+        if let Some(trap) = trap {
+            return Err(trap);
+        }
+    }
+}
+
+/// Placeholder for a result slot, overwritten by the call.
+fn default_val(val_type: ValType) -> Val {
+    match val_type {
+        ValType::I32 => Val::I32(0),
+        ValType::I64 => Val::I64(0),
+        ValType::F32 => Val::F32(0),
+        ValType::F64 => Val::F64(0),
+        ValType::V128 => Val::V128(0),
+        ValType::ExternRef => Val::ExternRef(None),
+        ValType::FuncRef => Val::FuncRef(None),
+    }
+}
+
+fn create_store(
+    module_template: &InitializedModule<WasmModuleStore, ModuleRuntimeConfig>,
+    mqtt_connection: Option<MqttConnection>,
+    control: Arc<ModuleControl>,
+    feature_flags: FeatureFlags,
+    logs: Arc<LogBuffer>,
+    denied_host_calls: Arc<AtomicU64>,
+    limiter: ModuleLimiter,
+) -> Store<WasmModuleStore> {
+    let watchdog_timeout = module_template
+        .runtime_config
+        .watchdog_timeout_ms
+        .map(Duration::from_millis);
+
+    let mut store = Store::new(
+        &module_template.engine,
+        WasmModuleStore {
+            mqtt_connection,
+            control,
+            limiter,
+            host_calls: HostCallPolicy::new(&module_template.runtime_config, denied_host_calls),
+            feature_flags,
+            logs,
+            extension: None,
+        },
+    );
+    store.limiter(|store| &mut store.limiter);
+    store.epoch_deadline_callback(move |store| on_epoch_deadline(store, watchdog_timeout));
+    store.set_epoch_deadline(1);
+
+    store
+}
+
+/// Resolves the module's imports against the linker without running any guest code.
+fn link(
+    module_template: &InitializedModule<WasmModuleStore, ModuleRuntimeConfig>,
+    store: &mut Store<WasmModuleStore>,
+) -> anyhow::Result<InstancePre<WasmModuleStore>> {
+    if !module_template
+        .runtime_config
+        .debug_api_enabled
+        .unwrap_or(true)
+    {
+        reject_imports_from(
+            &module_template.module,
+            "debug",
+            "the debug API is disabled for this module (`debug_api_enabled = false`)",
+        )?;
+    }
+
+    module_template
+        .linker
+        .instantiate_pre(&mut *store, &module_template.module)
+        .map_err(|e| {
+            explain_instantiate_error(e, &module_template.linker, store, &module_template.module)
+        })
+}
+
+/// Initializes the module's memories and tables and runs its start function, interrupted
+/// once `instantiate_timeout` has passed. This is guest code, so it has to run on the
+/// blocking pool.
+fn instantiate_linked(
+    instance_pre: &InstancePre<WasmModuleStore>,
+    store: &mut Store<WasmModuleStore>,
+    instantiate_timeout: Option<Duration>,
+) -> anyhow::Result<Instance> {
+    let control = store.data().control.clone();
+
+    control.set_instantiate_deadline(instantiate_timeout.map(|timeout| Instant::now() + timeout));
+
+    let instance = instance_pre.instantiate(&mut *store);
+
+    control.set_instantiate_deadline(None);
+
+    instance.map_err(|e| match instantiate_timeout {
+        Some(timeout) if control.instantiate_timed_out() => InstantiateTimeout(timeout).into(),
+        _ => e,
+    })
+}
+
+/// Fails unless `module` exports `start` as a function without params or results.
+fn check_entrypoint(module: &Module) -> anyhow::Result<()> {
+    match module.get_export("start") {
+        Some(ExternType::Func(func_type))
+            if func_type.params().next().is_none() && func_type.results().next().is_none() =>
+        {
+            Ok(())
+        }
+        Some(extern_type) => Err(anyhow!(
+            "`start` is a {} of type {}, expected a function of type () -> ()",
+            extern_type_kind(&extern_type),
+            extern_type_signature(&extern_type)
+        )),
+        None => Err(anyhow!("the module exports no `start` function")),
+    }
+}
+
+/// A failed instantiation in the module task ends the module like a trap in `start`.
+fn instantiate_trap(error: anyhow::Error) -> wasmtime::Trap {
+    match error.downcast::<wasmtime::Trap>() {
+        Ok(trap) => trap,
+        Err(error) => wasmtime::Trap::new(format!("instantiate: {:#}", error)),
+    }
+}
+
+fn module_exit(result: Result<(), wasmtime::Trap>, control: &ModuleControl) -> ModuleExit {
+    match result {
+        Ok(()) => ModuleExit::Finished,
+        Err(_) if control.instantiate_timed_out() => ModuleExit::InstantiateTimeout,
+        Err(_) if control.mqtt_deadline_expired() == Some(MqttDeadline::Connect) => {
+            ModuleExit::MqttConnectTimeout
+        }
+        Err(_) if control.mqtt_deadline_expired() == Some(MqttDeadline::Reconnect) => {
+            ModuleExit::MqttReconnectTimeout
+        }
+        Err(_) if control.stop_requested() => ModuleExit::Stopped,
+        Err(_) if control.watchdog_tripped() => ModuleExit::Watchdog,
+        Err(trap) => ModuleExit::Trapped(trap),
+    }
+}
+
+fn module_exit_event(module_name: &str, module_exit: &ModuleExit) -> AppEvent {
+    let name = module_name.to_string();
+
+    match module_exit {
+        ModuleExit::Finished => AppEvent::ModuleFinished { name },
+        ModuleExit::Stopped => AppEvent::ModuleStopped { name },
+        ModuleExit::Trapped(trap) => AppEvent::ModuleTrapped {
+            name,
+            trap: trap.to_string(),
+        },
+        ModuleExit::Watchdog => AppEvent::ModuleWatchdogExpired { name },
+        ModuleExit::MqttConnectTimeout => AppEvent::ModuleStartFailed {
+            name,
+            error: "MQTT connection timed out".to_string(),
+        },
+        ModuleExit::MqttReconnectTimeout => AppEvent::ModuleTrapped {
+            name,
+            trap: "MQTT reconnect timed out".to_string(),
+        },
+        ModuleExit::HostError(error) => AppEvent::ModuleHostError {
+            name,
+            error: error.clone(),
+        },
+        ModuleExit::InstantiateTimeout => AppEvent::ModuleStartFailed {
+            name,
+            error: "instantiation timed out".to_string(),
+        },
+    }
+}
+
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => match panic.downcast::<&'static str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "non-string panic payload".to_string(),
+        },
+    }
+}
+
+impl Drop for InitializedAppContext {
+    fn drop(&mut self) {
+        self.epoch_supervisor_task_handle.abort();
+
+        for event_sink_task_handle in &self.event_sink_task_handles {
+            event_sink_task_handle.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    const START_WAT: &str = r#"(module (func (export "start")))"#;
+
+    /// Writes `wat` to its own directory under the temp dir, so tests running in parallel
+    /// don't share files.
+    fn module_file(test_name: &str, wat: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("wasmtime-poc-{}-{}", std::process::id(), test_name));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join("module.wat");
+        std::fs::write(&path, wat).unwrap();
+
+        path
+    }
+
+    /// Config with the single module `m` loaded from `path`, and `engine` as the body of
+    /// the `[engine]` table.
+    fn single_module_config(path: &Path, engine: &str) -> AppConfig {
+        let config = format!(
+            "[engine]\n{}\n\n[modules.m]\nwasm_module_path = '{}'\n",
+            engine,
+            path.display()
+        );
+
+        AppConfig::from_reader(config.as_bytes(), ConfigFormat::Toml).unwrap()
+    }
+
+    async fn run_and_wait(context: &mut InitializedAppContext, module_name: &str) -> ModuleExit {
+        context.run_module(module_name).unwrap();
+        context.teardown_module(module_name).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn memory64_module_instantiates_with_memory64_enabled() {
+        let path = module_file(
+            "memory64",
+            r#"(module (memory i64 1) (func (export "start")))"#,
+        );
+
+        let mut context =
+            UninitializedAppContext::new(&single_module_config(&path, "memory64 = true"))
+                .unwrap()
+                .initialize_modules()
+                .unwrap();
+        let module_exit = run_and_wait(&mut context, "m").await;
+        assert!(
+            matches!(module_exit, ModuleExit::Finished),
+            "{:?}",
+            module_exit
+        );
+
+        let without_memory64 = UninitializedAppContext::new(&single_module_config(&path, ""))
+            .unwrap()
+            .initialize_modules();
+        assert!(matches!(without_memory64, Err(InitError::Compile { .. })));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn modules_run_to_completion_on_a_current_thread_runtime() {
+        let finishing = module_file("current-thread-finishing", START_WAT);
+        let trapping = module_file(
+            "current-thread-trapping",
+            r#"(module (func (export "start") unreachable))"#,
+        );
+        let config = format!(
+            "[modules.finishing]\nwasm_module_path = '{}'\n\n\
+             [modules.trapping]\nwasm_module_path = '{}'\n",
+            finishing.display(),
+            trapping.display()
+        );
+        let config = AppConfig::from_reader(config.as_bytes(), ConfigFormat::Toml).unwrap();
+
+        let mut context = UninitializedAppContext::new(&config)
+            .unwrap()
+            .initialize_modules()
+            .unwrap();
+        let mut exits: HashMap<String, ModuleExit> = context
+            .run_to_completion()
+            .await
+            .unwrap()
+            .into_iter()
+            .collect();
+
+        assert!(matches!(
+            exits.remove("finishing"),
+            Some(ModuleExit::Finished)
+        ));
+        assert!(matches!(
+            exits.remove("trapping"),
+            Some(ModuleExit::Trapped(_))
+        ));
+        assert_eq!(context.aggregate_exit_code(), 1);
+    }
+
+    struct PanickingExtension;
+
+    impl HostExtension for PanickingExtension {
+        fn store_data(&self, _module_name: &str) -> Box<dyn Any + Send> {
+            Box::new(())
+        }
+
+        fn add_to_linker(&self, linker: &mut Linker<WasmModuleStore>) -> anyhow::Result<()> {
+            linker.func_wrap("test", "panic", || panic!("host bug"))?;
+
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn panicking_host_function_is_reported_as_host_error() {
+        let path = module_file(
+            "host-panic",
+            r#"(module
+                (import "test" "panic" (func $panic))
+                (func (export "start") call $panic))"#,
+        );
+        let panics = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let handler_panics = panics.clone();
+
+        let mut context = UninitializedAppContext::new(&single_module_config(&path, ""))
+            .unwrap()
+            .with_host_extension(Arc::new(PanickingExtension))
+            .initialize_modules()
+            .unwrap();
+        context.set_host_panic_handler(Arc::new(move |module_name, message| {
+            handler_panics
+                .lock()
+                .unwrap()
+                .push((module_name.to_string(), message.to_string()));
+        }));
+        let module_exit = run_and_wait(&mut context, "m").await;
+
+        assert!(
+            matches!(&module_exit, ModuleExit::HostError(message) if message == "host bug"),
+            "{:?}",
+            module_exit
+        );
+        assert_eq!(
+            *panics.lock().unwrap(),
+            [("m".to_string(), "host bug".to_string())]
+        );
+        assert_eq!(context.aggregate_exit_code(), 5);
+    }
+
+    #[tokio::test]
+    async fn module_runs_under_each_supported_compiler() {
+        let path = module_file("compilers", START_WAT);
+
+        for compiler in [Compiler::Cranelift, Compiler::Winch] {
+            let compiler_name = match compiler {
+                Compiler::Cranelift => "cranelift",
+                Compiler::Winch => "winch",
+            };
+            let engine = format!("compiler = '{}'", compiler_name);
+            let context = UninitializedAppContext::new(&single_module_config(&path, &engine))
+                .unwrap()
+                .initialize_modules();
+
+            match compiler {
+                Compiler::Cranelift => {
+                    let module_exit = run_and_wait(&mut context.unwrap(), "m").await;
+                    assert!(
+                        matches!(module_exit, ModuleExit::Finished),
+                        "{}: {:?}",
+                        compiler_name,
+                        module_exit
+                    );
+                }
+                Compiler::Winch => assert!(matches!(context, Err(InitError::Config(_)))),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn empty_config_runs_without_modules() {
+        let config = AppConfig::from_reader("".as_bytes(), ConfigFormat::Toml).unwrap();
+
+        let mut context = UninitializedAppContext::new(&config)
+            .unwrap()
+            .initialize_modules()
+            .unwrap();
+        context.run_all_modules().unwrap();
+
+        assert!(context.module_names().is_empty());
+        assert!(context.run_to_completion().await.unwrap().is_empty());
+        assert_eq!(context.aggregate_exit_code(), 0);
+    }
+
+    #[test]
+    fn engine_builds_with_each_available_profiler() {
+        for profiler in [
+            Profiler::None,
+            Profiler::JitDump,
+            Profiler::PerfMap,
+            Profiler::VTune,
+        ] {
+            let engine_config = EngineConfig {
+                profiler,
+                ..EngineConfig::default()
+            };
+            let engine = engine_config
+                .to_wasmtime_config()
+                .map_err(anyhow::Error::from)
+                .and_then(|config| Engine::new(&config));
+
+            let available = match profiler {
+                Profiler::None => true,
+                Profiler::JitDump => cfg!(target_os = "linux"),
+                Profiler::PerfMap => false,
+                Profiler::VTune => cfg!(all(target_os = "linux", target_arch = "x86_64")),
+            };
+            assert_eq!(engine.is_ok(), available, "{:?}", profiler);
+        }
+    }
+
+    #[test]
+    fn duplicate_module_name_is_rejected_in_toml_and_json() {
+        let toml = r#"
+            [modules.a]
+            wasm_module_path = "a.wasm"
+
+            [modules.a]
+            wasm_module_path = "b.wasm"
+        "#;
+        let json = r#"{
+            "modules": {
+                "a": { "wasm_module_path": "a.wasm" },
+                "a": { "wasm_module_path": "b.wasm" }
+            }
+        }"#;
+
+        assert!(AppConfig::from_reader(toml.as_bytes(), ConfigFormat::Toml).is_err());
+
+        match AppConfig::from_reader(json.as_bytes(), ConfigFormat::Json) {
+            Err(ConfigError::Format { source, .. }) => {
+                assert!(source.to_string().contains("defined more than once"))
+            }
+            Err(e) => panic!("unexpected error: {:?}", e),
+            Ok(_) => panic!("duplicate module in JSON was accepted"),
+        }
     }
 }
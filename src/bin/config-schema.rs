@@ -0,0 +1,10 @@
+//! Prints the JSON Schema of the app config file, e.g. for validating configs in CI.
+
+fn main() -> anyhow::Result<()> {
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&wasmtime_poc::app::config_schema())?
+    );
+
+    Ok(())
+}
@@ -0,0 +1,40 @@
+//! Compiles every module of an app config ahead of time, writing a `.cwasm` artifact next
+//! to each module file. Point the config at the `.cwasm` files to skip compilation on
+//! startup.
+
+use clap::Parser;
+use wasmtime::Engine;
+use wasmtime_poc::app::{precompile_module, AppConfig};
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct Args {
+    #[clap(short, long, value_parser)]
+    app_config_path: String,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    let app_config = AppConfig::from_app_config_file(args.app_config_path)?;
+    let engine = Engine::new(&app_config.engine.to_wasmtime_config()?)?;
+
+    for (module_name, wasm_module_path) in app_config.module_paths()? {
+        if wasm_module_path.extension() == Some("cwasm".as_ref()) {
+            continue;
+        }
+
+        let wasm = wat::parse_file(&wasm_module_path)?;
+        let cwasm_path = wasm_module_path.with_extension("cwasm");
+
+        std::fs::write(&cwasm_path, precompile_module(&engine, &wasm)?)?;
+
+        println!(
+            "Precompiled module '{}' to '{}'",
+            module_name,
+            cwasm_path.display()
+        );
+    }
+
+    Ok(())
+}
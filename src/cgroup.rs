@@ -0,0 +1,113 @@
+use std::path::Path;
+
+use schemars::JsonSchema;
+use serde_derive::{Deserialize, Serialize};
+
+/// Linux cgroup v2 the thread running the module's `start` is moved into while it runs,
+/// as a second line of defense next to the wasm sandbox. This is best-effort: when the
+/// thread can't be moved, or on other platforms, a warning is logged and the module runs
+/// unconfined.
+///
+/// Only the entrypoint thread is moved, so the cgroup has to be `threaded` (see
+/// `cgroup.type`) and in the thread subtree of the cgroup this process runs in. Moving
+/// needs write access to `cgroup.threads` of both cgroups, i.e. root or a delegated
+/// subtree, e.g. `Delegate=yes` under systemd. Memory can't be capped this way because the
+/// memory controller doesn't support threaded cgroups; use `max_memory_bytes`.
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Debug)]
+pub struct CgroupConfig {
+    /// Directory of the cgroup, e.g. `/sys/fs/cgroup/wasmtime-poc/sensor`. Required.
+    pub path: Option<Box<Path>>,
+    /// Written to the cgroup's `cpu.weight` (1 to 10000, default 100) when the module
+    /// starts.
+    pub cpu_weight: Option<u32>,
+}
+
+/// Moves the current thread back to its previous cgroup when dropped, so the blocking
+/// pool thread isn't left confined when it runs something else next.
+pub struct CgroupGuard {
+    #[cfg(target_os = "linux")]
+    previous: std::path::PathBuf,
+}
+
+/// Moves the current thread into the cgroup, see [`CgroupConfig`].
+pub fn enter(module_name: &str, cgroup_config: &CgroupConfig) -> Option<CgroupGuard> {
+    // Checked when the config is loaded.
+    let path = cgroup_config.path.as_deref()?;
+
+    match enter_cgroup(path, cgroup_config.cpu_weight) {
+        Ok(guard) => Some(guard),
+        Err(e) => {
+            tracing::warn!(
+                "running module '{}' outside of cgroup '{}': {}",
+                module_name,
+                path.display(),
+                e
+            );
+            None
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+#[cfg(target_os = "linux")]
+fn enter_cgroup(path: &Path, cpu_weight: Option<u32>) -> std::io::Result<CgroupGuard> {
+    let previous = current_cgroup()?;
+
+    if let Some(cpu_weight) = cpu_weight {
+        std::fs::write(path.join("cpu.weight"), cpu_weight.to_string())?;
+    }
+
+    move_current_thread(path)?;
+
+    Ok(CgroupGuard { previous })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn enter_cgroup(_path: &Path, _cpu_weight: Option<u32>) -> std::io::Result<CgroupGuard> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "cgroups are only supported on Linux",
+    ))
+}
+
+/// Directory of the current thread's cgroup v2.
+#[cfg(target_os = "linux")]
+fn current_cgroup() -> std::io::Result<std::path::PathBuf> {
+    let cgroups = std::fs::read_to_string("/proc/thread-self/cgroup")?;
+
+    // The unified hierarchy is the one with ID 0 and no controllers.
+    cgroups
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(|cgroup| Path::new(CGROUP_ROOT).join(cgroup.trim_start_matches('/')))
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "no cgroup v2 hierarchy"))
+}
+
+#[cfg(target_os = "linux")]
+fn move_current_thread(cgroup: &Path) -> std::io::Result<()> {
+    // `/proc/thread-self` links to `<pid>/task/<tid>`.
+    let thread_self = std::fs::read_link("/proc/thread-self")?;
+    let tid = thread_self
+        .file_name()
+        .and_then(|tid| tid.to_str())
+        .ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "can't determine thread ID")
+        })?;
+
+    std::fs::write(cgroup.join("cgroup.threads"), tid)
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for CgroupGuard {
+    fn drop(&mut self) {
+        if let Err(e) = move_current_thread(&self.previous) {
+            tracing::warn!(
+                "can't move thread back to cgroup '{}': {}",
+                self.previous.display(),
+                e
+            );
+        }
+    }
+}
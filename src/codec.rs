@@ -0,0 +1,37 @@
+use std::sync::Arc;
+
+use crate::topic::topic_matches;
+
+/// Host-side transformation applied to incoming MQTT payloads before the guest sees
+/// them, e.g. to turn protobuf or CBOR into a canonical form the guest understands.
+pub trait MqttCodec: Send + Sync {
+    /// Returns the payload to deliver, or an error to drop a malformed message.
+    fn decode(&self, topic: &str, payload: &[u8]) -> Result<Vec<u8>, String>;
+}
+
+#[derive(Clone)]
+pub struct MqttCodecRegistration {
+    pub name: String,
+    pub topic_filter: String,
+    pub codec: Arc<dyn MqttCodec>,
+}
+
+/// Applies the first registered codec whose topic filter matches `topic`. Payloads on
+/// topics without a codec are passed through untouched.
+pub fn decode_payload(
+    codecs: &[MqttCodecRegistration],
+    topic: &str,
+    payload: &[u8],
+) -> Result<Option<Vec<u8>>, String> {
+    match codecs
+        .iter()
+        .find(|registration| topic_matches(&registration.topic_filter, topic))
+    {
+        Some(registration) => registration
+            .codec
+            .decode(topic, payload)
+            .map(Some)
+            .map_err(|e| format!("codec '{}' rejected message: {}", registration.name, e)),
+        None => Ok(None),
+    }
+}
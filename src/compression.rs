@@ -0,0 +1,72 @@
+use std::{
+    ffi::OsStr,
+    fmt,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Compression of a module file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    fn from_extension(path: &Path) -> Option<Compression> {
+        match path.extension().and_then(OsStr::to_str) {
+            Some("gz") => Some(Compression::Gzip),
+            Some("zst") => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Compression named by the path's extension (`.gz`, `.zst`), or else recognized by
+    /// the magic bytes at the start of `bytes`.
+    pub fn detect(path: &Path, bytes: &[u8]) -> Option<Compression> {
+        Compression::from_extension(path).or_else(|| {
+            if bytes.starts_with(GZIP_MAGIC) {
+                Some(Compression::Gzip)
+            } else if bytes.starts_with(ZSTD_MAGIC) {
+                Some(Compression::Zstd)
+            } else {
+                None
+            }
+        })
+    }
+
+    pub fn decompress(self, bytes: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut decompressed = Vec::new();
+
+        match self {
+            Compression::Gzip => {
+                flate2::read::GzDecoder::new(bytes).read_to_end(&mut decompressed)?;
+            }
+            Compression::Zstd => {
+                zstd::stream::read::Decoder::new(bytes)?.read_to_end(&mut decompressed)?;
+            }
+        }
+
+        Ok(decompressed)
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::Gzip => f.write_str("gzip"),
+            Compression::Zstd => f.write_str("zstd"),
+        }
+    }
+}
+
+/// `path` without its compression extension, e.g. `module.wasm` for `module.wasm.gz`.
+pub fn uncompressed_path(path: &Path) -> PathBuf {
+    match Compression::from_extension(path) {
+        Some(_) => path.with_extension(""),
+        None => path.to_path_buf(),
+    }
+}
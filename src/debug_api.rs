@@ -4,14 +4,32 @@ export!("./wit-bindgen/debug.wit");
 
 pub use debug::add_to_linker;
 
-use crate::module::WasmModuleStore;
+use crate::module::{LogStream, WasmModuleStore};
 
 impl debug::Debug for WasmModuleStore {
     fn sout(&mut self, msg: &str) {
-        println!("{}", msg);
+        if self.host_calls.check("debug::sout").is_err() {
+            return;
+        }
+
+        self.logs.push(LogStream::Stdout, msg);
+        println!("[{}] {}", self.control.instance_label(), msg);
     }
 
     fn serr(&mut self, msg: &str) {
-        eprintln!("{}", msg)
+        if self.host_calls.check("debug::serr").is_err() {
+            return;
+        }
+
+        self.logs.push(LogStream::Stderr, msg);
+        eprintln!("[{}] {}", self.control.instance_label(), msg)
+    }
+
+    fn memory_pressure(&mut self) -> bool {
+        if self.host_calls.check("debug::memory-pressure").is_err() {
+            return false;
+        }
+
+        self.limiter.memory_pressure()
     }
 }
@@ -0,0 +1,127 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    num::NonZeroUsize,
+};
+
+use schemars::JsonSchema;
+use serde_derive::{Deserialize, Serialize};
+
+/// Drops incoming messages whose id was seen among the last `window_size` ids, e.g. QoS 1
+/// redeliveries. The id is read from the payload, after codecs, since connections use
+/// MQTT 3.1.1 and messages carry no properties like correlation data. Messages without an
+/// id are always delivered.
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Debug)]
+pub struct DedupConfig {
+    /// JSON pointer to the id in a JSON payload, e.g. `/meta/id`. Strings and numbers
+    /// are used as is, other values in their JSON form.
+    pub key_pointer: String,
+    /// Number of most recent ids remembered.
+    pub window_size: NonZeroUsize,
+}
+
+pub struct Deduplicator {
+    key_pointer: String,
+    window_size: usize,
+    seen: HashSet<String>,
+    /// `seen` in arrival order, to forget the oldest id first.
+    order: VecDeque<String>,
+}
+
+impl Deduplicator {
+    pub fn new(dedup_config: &DedupConfig) -> Deduplicator {
+        Deduplicator {
+            key_pointer: dedup_config.key_pointer.clone(),
+            window_size: dedup_config.window_size.get(),
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Whether a message with the same id is still in the window. Records the id if not.
+    pub fn is_duplicate(&mut self, payload: &[u8]) -> bool {
+        let key = match self.key(payload) {
+            Some(key) => key,
+            None => return false,
+        };
+
+        if self.seen.contains(&key) {
+            return true;
+        }
+
+        if self.order.len() == self.window_size {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+
+        self.seen.insert(key.clone());
+        self.order.push_back(key);
+
+        false
+    }
+
+    fn key(&self, payload: &[u8]) -> Option<String> {
+        let payload: serde_json::Value = serde_json::from_slice(payload).ok()?;
+
+        match payload.pointer(&self.key_pointer)? {
+            serde_json::Value::String(key) => Some(key.clone()),
+            serde_json::Value::Null => None,
+            key => Some(key.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deduplicator(window_size: usize) -> Deduplicator {
+        Deduplicator::new(&DedupConfig {
+            key_pointer: "/id".to_string(),
+            window_size: NonZeroUsize::new(window_size).unwrap(),
+        })
+    }
+
+    #[test]
+    fn repeated_id_is_a_duplicate() {
+        let mut deduplicator = deduplicator(4);
+
+        assert!(!deduplicator.is_duplicate(br#"{"id": "a"}"#));
+        assert!(deduplicator.is_duplicate(br#"{"id": "a", "value": 1}"#));
+        assert!(!deduplicator.is_duplicate(br#"{"id": "b"}"#));
+    }
+
+    #[test]
+    fn ids_compare_by_their_text() {
+        let mut deduplicator = deduplicator(4);
+
+        assert!(!deduplicator.is_duplicate(br#"{"id": 1}"#));
+        assert!(deduplicator.is_duplicate(br#"{"id": 1}"#));
+        assert!(deduplicator.is_duplicate(br#"{"id": "1"}"#));
+        assert!(!deduplicator.is_duplicate(br#"{"id": {"n": 1}}"#));
+        assert!(deduplicator.is_duplicate(br#"{"id": {"n": 1}}"#));
+    }
+
+    #[test]
+    fn messages_without_id_are_never_duplicates() {
+        let mut deduplicator = deduplicator(4);
+
+        for payload in [&b"not json"[..], br#"{}"#, br#"{"id": null}"#] {
+            assert!(!deduplicator.is_duplicate(payload));
+            assert!(!deduplicator.is_duplicate(payload));
+        }
+    }
+
+    #[test]
+    fn oldest_id_leaves_the_window_first() {
+        let mut deduplicator = deduplicator(2);
+
+        assert!(!deduplicator.is_duplicate(br#"{"id": "a"}"#));
+        assert!(!deduplicator.is_duplicate(br#"{"id": "b"}"#));
+        assert!(!deduplicator.is_duplicate(br#"{"id": "c"}"#));
+
+        assert!(deduplicator.is_duplicate(br#"{"id": "c"}"#));
+        assert!(deduplicator.is_duplicate(br#"{"id": "b"}"#));
+        assert!(!deduplicator.is_duplicate(br#"{"id": "a"}"#));
+    }
+}
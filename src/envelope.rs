@@ -0,0 +1,48 @@
+use std::time::SystemTime;
+
+use schemars::JsonSchema;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::snapshot::unix_millis;
+
+/// Replaces the payload of incoming MQTT messages with an envelope carrying the message
+/// and its metadata, so guests don't have to piece that together from the poll events.
+/// Each variant is a fixed format; new fields mean a new version, so a guest keeps
+/// getting the format it was written against.
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageEnvelope {
+    /// JSON object with `version` (1), `topic`, `payload` (the bytes after codecs, as
+    /// standard base64 with padding), `qos` (0 to 2), `retain`, `timestamp_ms` (when the host received the
+    /// message, milliseconds since the Unix epoch) and `properties`. `properties` is
+    /// always empty since connections use MQTT 3.1.1, which has none.
+    V1,
+}
+
+#[derive(Serialize)]
+struct EnvelopeV1<'a> {
+    version: u32,
+    topic: &'a str,
+    payload: String,
+    qos: u8,
+    retain: bool,
+    timestamp_ms: u64,
+    properties: serde_json::Map<String, serde_json::Value>,
+}
+
+impl MessageEnvelope {
+    pub fn wrap(&self, publish: &rumqttc::Publish, received_at: SystemTime) -> Vec<u8> {
+        match self {
+            MessageEnvelope::V1 => serde_json::to_vec(&EnvelopeV1 {
+                version: 1,
+                topic: &publish.topic,
+                payload: base64::encode(&publish.payload),
+                qos: publish.qos as u8,
+                retain: publish.retain,
+                timestamp_ms: unix_millis(received_at),
+                properties: serde_json::Map::new(),
+            })
+            .expect("envelope is always serializable"),
+        }
+    }
+}
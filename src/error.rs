@@ -0,0 +1,102 @@
+//! Messages describe only their own layer and leave the cause to `source()`, so render
+//! them with the whole chain, e.g. `{:#}` after converting to `anyhow::Error`.
+
+use std::{path::Path, time::Duration};
+
+use crate::{app::ConfigFormat, compression::Compression};
+
+/// Problems with the app config itself, before any module is loaded.
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("can't read config file '{}'", path.display())]
+    Io {
+        path: Box<Path>,
+        source: std::io::Error,
+    },
+    #[error("invalid config file '{}'", path.display())]
+    Parse {
+        path: Box<Path>,
+        source: toml::de::Error,
+    },
+    /// Reading a config passed as a stream, see [`crate::app::AppConfig::from_reader`].
+    #[error("can't read config")]
+    Read(#[source] std::io::Error),
+    #[error("invalid {format} config")]
+    Format {
+        format: ConfigFormat,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    /// The config parsed, but a value in it can't be used.
+    #[error("invalid config value: {0}")]
+    InvalidValue(String),
+}
+
+/// Instantiating a module, i.e. running its start function and initializing its memory,
+/// exceeded `instantiate_timeout_ms`.
+#[derive(Debug, thiserror::Error)]
+#[error("instantiation exceeded instantiate_timeout_ms ({0:?})")]
+pub struct InstantiateTimeout(pub Duration);
+
+/// Why [`crate::app::InitializedAppContext::wait_until_ready`] gave up.
+#[derive(Debug, thiserror::Error)]
+pub enum ReadyError {
+    #[error("unknown module '{0}'")]
+    UnknownModule(String),
+    #[error("module '{0}' is not running")]
+    NotRunning(String),
+    /// The module ended, e.g. trapped or hit an MQTT deadline, without calling
+    /// `mark-ready`.
+    #[error("module '{0}' exited before it was ready")]
+    Exited(String),
+    #[error("module '{module}' not ready within {timeout:?}")]
+    Timeout { module: String, timeout: Duration },
+}
+
+/// Errors of [`crate::app::UninitializedAppContext`]'s constructor and `initialize_*`
+/// methods, and of the initial start of the modules under `startup_deadline_ms`.
+#[derive(Debug, thiserror::Error)]
+pub enum InitError {
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    #[error("module '{module}': file '{}' not found", path.display())]
+    ModuleNotFound { module: String, path: Box<Path> },
+    #[error("can't load module '{module}' from '{}'", path.display())]
+    ModuleLoad {
+        module: String,
+        path: Box<Path>,
+        source: anyhow::Error,
+    },
+    /// The file looked compressed but couldn't be decompressed, as opposed to
+    /// [`InitError::Compile`] for files that aren't valid wasm.
+    #[error("can't decompress {compression} module '{module}' from '{}'", path.display())]
+    ModuleDecompress {
+        module: String,
+        path: Box<Path>,
+        compression: Compression,
+        source: std::io::Error,
+    },
+    #[error("can't compile module '{module}'")]
+    Compile {
+        module: String,
+        source: anyhow::Error,
+    },
+    #[error("can't create engine")]
+    Engine(#[source] anyhow::Error),
+    #[error(
+        "startup exceeded startup_deadline_ms, modules not started yet: {}",
+        pending_modules.join(", ")
+    )]
+    StartupTimeout { pending_modules: Vec<String> },
+    #[error("can't read state file '{}'", path.display())]
+    StateFile {
+        path: Box<Path>,
+        source: anyhow::Error,
+    },
+    /// Adding the built-in APIs or a [`crate::extension::HostExtension`]'s host functions
+    /// to the module's linker failed, e.g. on a name clash.
+    #[error("can't set up the linker for module '{module}'")]
+    Linker {
+        module: String,
+        source: anyhow::Error,
+    },
+}
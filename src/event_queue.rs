@@ -0,0 +1,252 @@
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::{mpsc::error::TryRecvError, Semaphore};
+
+use crate::topic::topic_matches;
+
+/// Bounded queue of MQTT events from a module's event loop to its guest. Incoming
+/// publishes are handed out by the priority of their topic, highest first, see
+/// `topic_priorities`. Events of equal priority keep their arrival order.
+pub fn event_queue(
+    capacity: usize,
+    topic_priorities: HashMap<String, i32>,
+) -> (EventSender, EventReceiver) {
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(Queue::default()),
+        space: Semaphore::new(capacity),
+        topic_priorities,
+    });
+
+    (
+        EventSender {
+            shared: shared.clone(),
+        },
+        EventReceiver { shared },
+    )
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    /// One permit per free slot. Closed once the receiver is dropped.
+    space: Semaphore,
+    topic_priorities: HashMap<String, i32>,
+}
+
+impl Shared {
+    /// Highest priority of all filters matching the topic, 0 if none does. Events other
+    /// than incoming publishes have priority 0.
+    fn priority(&self, event: &rumqttc::Event) -> i32 {
+        match event {
+            rumqttc::Event::Incoming(rumqttc::Incoming::Publish(publish)) => self
+                .topic_priorities
+                .iter()
+                .filter(|(topic_filter, _)| topic_matches(topic_filter, &publish.topic))
+                .map(|(_, priority)| *priority)
+                .max()
+                .unwrap_or(0),
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    events: BinaryHeap<QueuedEvent>,
+    next_seq: u64,
+}
+
+struct QueuedEvent {
+    priority: i32,
+    seq: u64,
+    event: rumqttc::Event,
+}
+
+impl Ord for QueuedEvent {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for QueuedEvent {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for QueuedEvent {
+    fn eq(&self, other: &Self) -> bool {
+        self.seq == other.seq
+    }
+}
+
+impl Eq for QueuedEvent {}
+
+#[derive(Debug)]
+pub struct EventQueueClosed;
+
+impl std::fmt::Display for EventQueueClosed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("event queue closed")
+    }
+}
+
+impl std::error::Error for EventQueueClosed {}
+
+#[derive(Clone)]
+pub struct EventSender {
+    shared: Arc<Shared>,
+}
+
+impl EventSender {
+    /// Waits for room in the queue. Fails once the receiver is dropped.
+    pub async fn send(&self, event: rumqttc::Event) -> Result<(), EventQueueClosed> {
+        self.shared
+            .space
+            .acquire()
+            .await
+            .map_err(|_| EventQueueClosed)?
+            .forget();
+
+        let priority = self.shared.priority(&event);
+        let mut queue = self.shared.queue.lock().unwrap();
+        let seq = queue.next_seq;
+
+        queue.next_seq += 1;
+        queue.events.push(QueuedEvent {
+            priority,
+            seq,
+            event,
+        });
+
+        Ok(())
+    }
+}
+
+pub struct EventReceiver {
+    shared: Arc<Shared>,
+}
+
+impl EventReceiver {
+    /// The queued event with the highest priority. `Disconnected` once the queue is empty
+    /// and every sender is gone.
+    pub fn try_recv(&mut self) -> Result<rumqttc::Event, TryRecvError> {
+        let queued_event = self.shared.queue.lock().unwrap().events.pop();
+
+        match queued_event {
+            Some(queued_event) => {
+                self.shared.space.add_permits(1);
+                Ok(queued_event.event)
+            }
+            // Senders are the only other owners of `shared`.
+            None if Arc::strong_count(&self.shared) == 1 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+}
+
+impl Drop for EventReceiver {
+    fn drop(&mut self) {
+        self.shared.space.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rumqttc::{Event, Incoming, Outgoing, Publish, QoS};
+
+    use super::*;
+
+    fn publish(topic: &str) -> Event {
+        Event::Incoming(Incoming::Publish(Publish::new(
+            topic,
+            QoS::AtMostOnce,
+            Vec::<u8>::new(),
+        )))
+    }
+
+    fn received_topics(receiver: &mut EventReceiver) -> Vec<String> {
+        let mut topics = vec![];
+
+        while let Ok(event) = receiver.try_recv() {
+            topics.push(match event {
+                Event::Incoming(Incoming::Publish(publish)) => publish.topic,
+                other => format!("{:?}", other),
+            });
+        }
+
+        topics
+    }
+
+    #[tokio::test]
+    async fn events_are_received_by_priority_then_arrival() {
+        let topic_priorities = HashMap::from([
+            ("alarm/#".to_string(), 10),
+            ("alarm/fire".to_string(), 20),
+            ("log/#".to_string(), -1),
+        ]);
+        let (sender, mut receiver) = event_queue(16, topic_priorities);
+
+        for event in [
+            publish("log/a"),
+            publish("data/1"),
+            publish("alarm/door"),
+            Event::Outgoing(Outgoing::PingReq),
+            publish("data/2"),
+            publish("alarm/fire"),
+            publish("alarm/window"),
+        ] {
+            sender.send(event).await.unwrap();
+        }
+
+        assert_eq!(
+            received_topics(&mut receiver),
+            [
+                "alarm/fire",
+                "alarm/door",
+                "alarm/window",
+                "data/1",
+                "Outgoing(PingReq)",
+                "data/2",
+                "log/a",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn send_waits_for_room_and_fails_once_receiver_is_gone() {
+        let (sender, mut receiver) = event_queue(1, HashMap::new());
+
+        sender.send(publish("a")).await.unwrap();
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), sender.send(publish("b")))
+                .await
+                .is_err()
+        );
+
+        assert_eq!(received_topics(&mut receiver), ["a"]);
+        sender.send(publish("c")).await.unwrap();
+
+        drop(receiver);
+        assert!(sender.send(publish("d")).await.is_err());
+    }
+
+    #[test]
+    fn receiver_is_disconnected_once_empty_without_senders() {
+        let (sender, mut receiver) = event_queue(1, HashMap::new());
+
+        assert!(matches!(receiver.try_recv(), Err(TryRecvError::Empty)));
+        drop(sender);
+        assert!(matches!(
+            receiver.try_recv(),
+            Err(TryRecvError::Disconnected)
+        ));
+    }
+}
@@ -0,0 +1,19 @@
+use std::any::Any;
+
+use wasmtime::Linker;
+
+use crate::module::WasmModuleStore;
+
+/// Embedder-provided host functions and the per-store state they work on. The state
+/// lives next to the built-in APIs' in [`WasmModuleStore`] rather than replacing it, so
+/// contexts without an extension keep working as they are; host functions get at it
+/// through [`WasmModuleStore::extension_mut`].
+pub trait HostExtension: Send + Sync {
+    /// State for a new store of module `module_name`. Called for every instance, i.e. on
+    /// each start or restart and for each `call_export` instance.
+    fn store_data(&self, module_name: &str) -> Box<dyn Any + Send>;
+
+    /// Adds the extension's host functions. Called once per module after the built-in
+    /// APIs were added, so names must not clash with theirs.
+    fn add_to_linker(&self, linker: &mut Linker<WasmModuleStore>) -> anyhow::Result<()>;
+}
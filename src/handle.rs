@@ -0,0 +1,306 @@
+use std::time::Duration;
+
+use anyhow::anyhow;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio_stream::Stream;
+
+use crate::{
+    app::{
+        app_event_stream, AppEvent, InitializedAppContext, ModuleExit, ModuleStatus,
+        SubscriptionResult, CLEANUP_INTERVAL,
+    },
+    error::ReadyError,
+    module::LogRecord,
+};
+
+const COMMAND_CHANNEL_CAPACITY: usize = 64;
+
+enum Command {
+    RunModule {
+        name: String,
+        reply: oneshot::Sender<anyhow::Result<()>>,
+    },
+    StopModule {
+        name: String,
+        reply: oneshot::Sender<anyhow::Result<Option<ModuleExit>>>,
+    },
+    ContinueModule {
+        name: String,
+        reply: oneshot::Sender<anyhow::Result<()>>,
+    },
+    CanaryStart {
+        name: String,
+        budget: Duration,
+        reply: oneshot::Sender<anyhow::Result<()>>,
+    },
+    RunGroup {
+        name: String,
+        reply: oneshot::Sender<anyhow::Result<()>>,
+    },
+    StopGroup {
+        name: String,
+        reply: oneshot::Sender<anyhow::Result<Vec<(String, ModuleExit)>>>,
+    },
+    ModuleStatus {
+        name: String,
+        reply: oneshot::Sender<Option<ModuleStatus>>,
+    },
+    RecentLogs {
+        name: String,
+        n: usize,
+        reply: oneshot::Sender<Vec<LogRecord>>,
+    },
+    ListModules {
+        reply: oneshot::Sender<Vec<(String, ModuleStatus)>>,
+    },
+    Resubscribe {
+        name: String,
+        reply: oneshot::Sender<anyhow::Result<Vec<SubscriptionResult>>>,
+    },
+    WaitUntilReady {
+        name: String,
+        timeout: Duration,
+        reply: oneshot::Sender<Result<(), ReadyError>>,
+    },
+    PauseAll,
+    ResumeAll,
+}
+
+/// Cloneable, `Send + Sync` handle to an [`InitializedAppContext`] owned by a dedicated
+/// task, see [`AppContextHandle::spawn`]. Every command is sent to that task over a
+/// channel and executed there one at a time, so once the context is handed over all
+/// mutation goes through the handle. The owning task also tears down modules that
+/// finished on their own, which is what the `cleanup_finished_modules` loop does
+/// otherwise. It exits, dropping the context, once the last handle is dropped.
+#[derive(Clone)]
+pub struct AppContextHandle {
+    commands: mpsc::Sender<Command>,
+    events: broadcast::Sender<AppEvent>,
+}
+
+impl AppContextHandle {
+    /// Moves `context` into a task on its runtime and returns a handle to it.
+    pub fn spawn(context: InitializedAppContext) -> AppContextHandle {
+        let (commands, command_receiver) = mpsc::channel(COMMAND_CHANNEL_CAPACITY);
+        let events = context.event_sender().clone();
+
+        context
+            .runtime_handle()
+            .clone()
+            .spawn(run_context(context, command_receiver));
+
+        AppContextHandle { commands, events }
+    }
+
+    pub async fn run_module(&self, name: &str) -> anyhow::Result<()> {
+        self.request(|reply| Command::RunModule {
+            name: name.to_string(),
+            reply,
+        })
+        .await?
+    }
+
+    pub async fn stop_module(&self, name: &str) -> anyhow::Result<Option<ModuleExit>> {
+        self.request(|reply| Command::StopModule {
+            name: name.to_string(),
+            reply,
+        })
+        .await?
+    }
+
+    pub async fn continue_module(&self, name: &str) -> anyhow::Result<()> {
+        self.request(|reply| Command::ContinueModule {
+            name: name.to_string(),
+            reply,
+        })
+        .await?
+    }
+
+    pub async fn canary_start(&self, name: &str, budget: Duration) -> anyhow::Result<()> {
+        self.request(|reply| Command::CanaryStart {
+            name: name.to_string(),
+            budget,
+            reply,
+        })
+        .await?
+    }
+
+    pub async fn run_group(&self, name: &str) -> anyhow::Result<()> {
+        self.request(|reply| Command::RunGroup {
+            name: name.to_string(),
+            reply,
+        })
+        .await?
+    }
+
+    pub async fn stop_group(&self, name: &str) -> anyhow::Result<Vec<(String, ModuleExit)>> {
+        self.request(|reply| Command::StopGroup {
+            name: name.to_string(),
+            reply,
+        })
+        .await?
+    }
+
+    pub async fn module_status(&self, name: &str) -> anyhow::Result<Option<ModuleStatus>> {
+        self.request(|reply| Command::ModuleStatus {
+            name: name.to_string(),
+            reply,
+        })
+        .await
+    }
+
+    pub async fn recent_logs(&self, name: &str, n: usize) -> anyhow::Result<Vec<LogRecord>> {
+        self.request(|reply| Command::RecentLogs {
+            name: name.to_string(),
+            n,
+            reply,
+        })
+        .await
+    }
+
+    /// Every module with its current status.
+    pub async fn list_modules(&self) -> anyhow::Result<Vec<(String, ModuleStatus)>> {
+        self.request(|reply| Command::ListModules { reply }).await
+    }
+
+    /// Same as [`InitializedAppContext::resubscribe`].
+    pub async fn resubscribe(&self, name: &str) -> anyhow::Result<Vec<SubscriptionResult>> {
+        self.request(|reply| Command::Resubscribe {
+            name: name.to_string(),
+            reply,
+        })
+        .await?
+    }
+
+    /// Same as [`InitializedAppContext::wait_until_ready`].
+    pub async fn wait_until_ready(&self, name: &str, timeout: Duration) -> anyhow::Result<()> {
+        Ok(self
+            .request(|reply| Command::WaitUntilReady {
+                name: name.to_string(),
+                timeout,
+                reply,
+            })
+            .await??)
+    }
+
+    pub async fn pause_all(&self) -> anyhow::Result<()> {
+        self.send(Command::PauseAll).await
+    }
+
+    pub async fn resume_all(&self) -> anyhow::Result<()> {
+        self.send(Command::ResumeAll).await
+    }
+
+    /// Same as [`InitializedAppContext::events`].
+    pub fn events(&self) -> impl Stream<Item = AppEvent> {
+        app_event_stream(self.events.subscribe())
+    }
+
+    async fn send(&self, command: Command) -> anyhow::Result<()> {
+        self.commands
+            .send(command)
+            .await
+            .map_err(|_| anyhow!("app context task has exited"))
+    }
+
+    async fn request<T>(
+        &self,
+        command: impl FnOnce(oneshot::Sender<T>) -> Command,
+    ) -> anyhow::Result<T> {
+        let (reply, reply_receiver) = oneshot::channel();
+
+        self.send(command(reply)).await?;
+
+        reply_receiver
+            .await
+            .map_err(|_| anyhow!("app context task has exited"))
+    }
+}
+
+async fn run_context(mut context: InitializedAppContext, mut commands: mpsc::Receiver<Command>) {
+    let mut cleanup_interval = tokio::time::interval(CLEANUP_INTERVAL);
+
+    loop {
+        tokio::select! {
+            command = commands.recv() => match command {
+                Some(command) => handle_command(&mut context, command).await,
+                None => break,
+            },
+            _ = cleanup_interval.tick() => {
+                if let Err(e) = context.cleanup_finished_modules().await {
+                    tracing::error!("Error cleaning up finished modules: {}", e);
+                }
+            }
+        }
+    }
+}
+
+async fn handle_command(context: &mut InitializedAppContext, command: Command) {
+    // A dropped reply receiver just means the caller stopped waiting.
+    match command {
+        Command::RunModule { name, reply } => {
+            let _ = reply.send(context.run_module(&name));
+        }
+        Command::StopModule { name, reply } => {
+            let _ = reply.send(context.stop_module(&name).await);
+        }
+        Command::ContinueModule { name, reply } => {
+            let _ = reply.send(context.continue_module(&name));
+        }
+        Command::CanaryStart {
+            name,
+            budget,
+            reply,
+        } => {
+            let _ = reply.send(context.canary_start(&name, budget).await);
+        }
+        Command::RunGroup { name, reply } => {
+            let _ = reply.send(context.run_group(&name));
+        }
+        Command::StopGroup { name, reply } => {
+            let _ = reply.send(context.stop_group(&name).await);
+        }
+        Command::ModuleStatus { name, reply } => {
+            let _ = reply.send(context.module_status(&name));
+        }
+        Command::RecentLogs { name, n, reply } => {
+            let _ = reply.send(context.recent_logs(&name, n));
+        }
+        Command::ListModules { reply } => {
+            let modules = context
+                .module_names()
+                .into_iter()
+                .filter_map(|name| {
+                    let status = context.module_status(&name)?;
+                    Some((name, status))
+                })
+                .collect();
+
+            let _ = reply.send(modules);
+        }
+        Command::Resubscribe { name, reply } => match context.request_resubscribe(&name) {
+            // Waiting for the SubAck mustn't hold up other commands.
+            Ok(result) => {
+                context.runtime_handle().spawn(async move {
+                    let _ = reply.send(result.await);
+                });
+            }
+            Err(e) => {
+                let _ = reply.send(Err(e));
+            }
+        },
+        Command::WaitUntilReady {
+            name,
+            timeout,
+            reply,
+        } => {
+            let ready = context.wait_until_ready(&name, timeout);
+
+            context.runtime_handle().spawn(async move {
+                let _ = reply.send(ready.await);
+            });
+        }
+        Command::PauseAll => context.pause_all(),
+        Command::ResumeAll => context.resume_all(),
+    }
+}
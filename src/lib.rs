@@ -0,0 +1,25 @@
+pub mod app;
+pub mod cgroup;
+pub mod codec;
+pub mod compression;
+mod debug_api;
+pub mod dedup;
+pub mod envelope;
+pub mod error;
+mod event_queue;
+pub mod extension;
+pub mod handle;
+mod linking;
+pub mod module;
+mod mqtt_api;
+pub mod mqtt_mock;
+#[cfg(feature = "rest-control")]
+pub mod rest_control;
+mod runtime_api;
+pub mod secret;
+pub mod sink;
+pub mod snapshot;
+pub mod state;
+#[cfg(feature = "test-util")]
+pub mod test_util;
+pub mod topic;
@@ -0,0 +1,167 @@
+use wasmtime::{ExternType, Linker, Module, Mutability, Store, ValType};
+
+pub(crate) fn extern_type_kind(extern_type: &ExternType) -> &'static str {
+    match extern_type {
+        ExternType::Func(_) => "function",
+        ExternType::Global(_) => "global",
+        ExternType::Table(_) => "table",
+        ExternType::Memory(_) => "memory",
+    }
+}
+
+fn val_types(val_types: impl Iterator<Item = ValType>) -> String {
+    val_types
+        .map(|val_type| val_type.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Human-readable signature, e.g. `(i32, i32) -> (i64)` for a function or
+/// `min 1, max 16 pages` for a memory.
+pub(crate) fn extern_type_signature(extern_type: &ExternType) -> String {
+    match extern_type {
+        ExternType::Func(func_type) => format!(
+            "({}) -> ({})",
+            val_types(func_type.params()),
+            val_types(func_type.results())
+        ),
+        ExternType::Global(global_type) => match global_type.mutability() {
+            Mutability::Const => global_type.content().to_string(),
+            Mutability::Var => format!("mut {}", global_type.content()),
+        },
+        ExternType::Table(table_type) => match table_type.maximum() {
+            Some(maximum) => format!(
+                "{}, min {}, max {}",
+                table_type.element(),
+                table_type.minimum(),
+                maximum
+            ),
+            None => format!("{}, min {}", table_type.element(), table_type.minimum()),
+        },
+        ExternType::Memory(memory_type) => {
+            let index_type = if memory_type.is_64() { "i64" } else { "i32" };
+
+            match memory_type.maximum() {
+                Some(maximum) => format!(
+                    "{}, min {}, max {} pages",
+                    index_type,
+                    memory_type.minimum(),
+                    maximum
+                ),
+                None => format!("{}, min {} pages", index_type, memory_type.minimum()),
+            }
+        }
+    }
+}
+
+/// Adds the first import of `module` the linker can't satisfy to an instantiation error,
+/// naming the import and the expected vs. provided type. Errors that aren't caused by an
+/// import mismatch are returned unchanged.
+pub fn explain_instantiate_error<T>(
+    error: anyhow::Error,
+    linker: &Linker<T>,
+    store: &mut Store<T>,
+    module: &Module,
+) -> anyhow::Error {
+    for import in module.imports() {
+        let expected = import.ty();
+
+        let provided = match linker.get(&mut *store, import.module(), import.name()) {
+            Some(provided) => provided.ty(&*store),
+            None => {
+                return error.context(format!(
+                    "unknown import `{}::{}`: the module expects a {} of type {} but the host \
+                     provides nothing under that name",
+                    import.module(),
+                    import.name(),
+                    extern_type_kind(&expected),
+                    extern_type_signature(&expected)
+                ))
+            }
+        };
+
+        if !import_satisfied_by(&expected, &provided) {
+            return error.context(format!(
+                "incompatible import `{}::{}`: the module expects a {} of type {} but the host \
+                 provides a {} of type {}",
+                import.module(),
+                import.name(),
+                extern_type_kind(&expected),
+                extern_type_signature(&expected),
+                extern_type_kind(&provided),
+                extern_type_signature(&provided)
+            ));
+        }
+    }
+
+    error
+}
+
+/// Whether `provided` can be used for an import of type `expected`, following the
+/// wasm import matching rules: functions and globals must match exactly, tables and
+/// memories may be larger than expected but must stay within the expected maximum.
+fn import_satisfied_by(expected: &ExternType, provided: &ExternType) -> bool {
+    fn limits_match(
+        expected_minimum: u64,
+        expected_maximum: Option<u64>,
+        provided_minimum: u64,
+        provided_maximum: Option<u64>,
+    ) -> bool {
+        provided_minimum >= expected_minimum
+            && match (expected_maximum, provided_maximum) {
+                (None, _) => true,
+                (Some(expected_maximum), Some(provided_maximum)) => {
+                    provided_maximum <= expected_maximum
+                }
+                (Some(_), None) => false,
+            }
+    }
+
+    match (expected, provided) {
+        (ExternType::Func(expected), ExternType::Func(provided)) => expected == provided,
+        (ExternType::Global(expected), ExternType::Global(provided)) => {
+            expected.content() == provided.content()
+                && expected.mutability() == provided.mutability()
+        }
+        (ExternType::Table(expected), ExternType::Table(provided)) => {
+            expected.element() == provided.element()
+                && limits_match(
+                    expected.minimum().into(),
+                    expected.maximum().map(u64::from),
+                    provided.minimum().into(),
+                    provided.maximum().map(u64::from),
+                )
+        }
+        (ExternType::Memory(expected), ExternType::Memory(provided)) => {
+            expected.is_64() == provided.is_64()
+                && limits_match(
+                    expected.minimum(),
+                    expected.maximum(),
+                    provided.minimum(),
+                    provided.maximum(),
+                )
+        }
+        _ => false,
+    }
+}
+
+/// Fails if `module` imports anything from `import_module`, giving `reason` for why the
+/// host doesn't provide it.
+pub fn reject_imports_from(
+    module: &Module,
+    import_module: &str,
+    reason: &str,
+) -> anyhow::Result<()> {
+    match module
+        .imports()
+        .find(|import| import.module() == import_module)
+    {
+        Some(import) => Err(anyhow::anyhow!(
+            "import `{}::{}` not available: {}",
+            import.module(),
+            import.name(),
+            reason
+        )),
+        None => Ok(()),
+    }
+}
@@ -1,35 +1,34 @@
 #![feature(hash_drain_filter)]
 
-mod app;
-mod debug_api;
-mod module;
-mod mqtt_api;
-
-use app::{AppConfig, UninitializedAppContext};
 use clap::Parser;
+use wasmtime_poc::app::{AppConfig, ConfigFormat, UninitializedAppContext};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
+    /// `-` reads the config from stdin.
     #[clap(short, long, value_parser)]
     app_config_path: String,
+    /// Format of a config read from stdin, `toml` or `json`.
+    #[clap(long, value_parser, default_value = "toml")]
+    config_format: ConfigFormat,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
 
-    let app_config = AppConfig::from_app_config_file(args.app_config_path)?;
+    // Warnings and errors of the runtime are reported through `tracing`.
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .init();
+
+    let app_config = match args.app_config_path.as_str() {
+        "-" => AppConfig::from_stdin(args.config_format)?,
+        path => AppConfig::from_app_config_file(path)?,
+    };
 
     let unitialized_app_context = UninitializedAppContext::new(&app_config)?;
     let mut initialized_app_context = unitialized_app_context.initialize_modules()?;
-    initialized_app_context.run_all_modules()?;
-
-    loop {
-        let cleaned_up = initialized_app_context.cleanup_finished_modules().await?;
-
-        if cleaned_up.len() > 0 {
-            println!("{:?}", cleaned_up);
-        }
-    }
+    initialized_app_context.run_until_signal().await
 }
@@ -1,28 +1,139 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::Duration;
 
 use rumqttc::Incoming;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::watch;
 use wit_bindgen_host_wasmtime_rust::export;
 export!("./wit-bindgen/mqtt.wit");
 
+use crate::event_queue::EventReceiver;
+use crate::module::WasmModuleStore;
+use crate::topic::{filter_covers, shared_subscription_filter, topic_matches};
+
 pub use mqtt::add_to_linker;
 
+/// Topic filters a module is subscribed to with their QoS, shared with its event loop so
+/// it can restore them after reconnecting with new credentials.
+pub type Subscriptions = Arc<Mutex<HashMap<String, rumqttc::QoS>>>;
+
 pub struct MqttConnection {
     client: Arc<Mutex<rumqttc::AsyncClient>>,
-    events: mpsc::Receiver<rumqttc::Event>,
+    events: EventReceiver,
     allowed_sub_topics: Vec<String>,
     allowed_pub_topics: Vec<String>,
+    stats: Arc<MqttConnectionStats>,
+    shutdown: watch::Receiver<bool>,
     rt: tokio::runtime::Runtime,
+    publish_batch_sender: Option<mpsc::Sender<OutboundPublish>>,
+    dead_letter_topic: Option<String>,
+    shared_subscription_group: Option<String>,
+    subscriptions: Subscriptions,
+    max_subscriptions: Option<usize>,
+}
+
+/// A guest publish waiting in the outbound batch.
+pub struct OutboundPublish {
+    topic: String,
+    qos: rumqttc::QoS,
+    retain: bool,
+    payload: Vec<u8>,
+}
+
+/// Collects guest publishes for up to `window` or until `max` are queued, then hands
+/// them to the event loop back to back. Publishes go through a single queue, so their
+/// order, and with it the order within each topic, is preserved. Runs until the
+/// module's `MqttConnection` is dropped, flushing whatever is still queued.
+pub struct PublishBatchTask {
+    pub client: rumqttc::AsyncClient,
+    pub stats: Arc<MqttConnectionStats>,
+    pub receiver: mpsc::Receiver<OutboundPublish>,
+    pub window: Duration,
+    pub max: usize,
+}
+
+impl PublishBatchTask {
+    pub async fn run(mut self) {
+        let mut batch = Vec::with_capacity(self.max);
+
+        while let Some(publish) = self.receiver.recv().await {
+            batch.push(publish);
+
+            let window = tokio::time::sleep(self.window);
+            tokio::pin!(window);
+
+            while batch.len() < self.max {
+                tokio::select! {
+                    publish = self.receiver.recv() => match publish {
+                        Some(publish) => batch.push(publish),
+                        None => break,
+                    },
+                    _ = &mut window => break,
+                }
+            }
+
+            for publish in batch.drain(..) {
+                if let Err(e) = self
+                    .client
+                    .publish(publish.topic, publish.qos, publish.retain, publish.payload)
+                    .await
+                {
+                    self.stats
+                        .record_error(format!("batched publish failed: {}", e));
+                }
+            }
+        }
+    }
+}
+
+/// Counters and connection state for a single module's MQTT connection, maintained by
+/// its event loop task.
+pub struct MqttConnectionStats {
+    pub published: AtomicU64,
+    pub received: AtomicU64,
+    pub reconnects: AtomicU64,
+    /// Incoming messages dropped as duplicates, see `dedup`.
+    pub deduplicated: AtomicU64,
+    pub connected: watch::Sender<bool>,
+    last_error: Mutex<Option<String>>,
+}
+
+impl Default for MqttConnectionStats {
+    fn default() -> Self {
+        MqttConnectionStats {
+            published: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+            reconnects: AtomicU64::new(0),
+            deduplicated: AtomicU64::new(0),
+            connected: watch::channel(false).0,
+            last_error: Mutex::new(None),
+        }
+    }
+}
+
+impl MqttConnectionStats {
+    pub fn record_error(&self, error: String) {
+        *self.last_error.lock().unwrap() = Some(error);
+    }
+
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.lock().unwrap().clone()
+    }
 }
 
 impl MqttConnection {
     pub fn new(
         client: rumqttc::AsyncClient,
-        events: mpsc::Receiver<rumqttc::Event>,
+        events: EventReceiver,
         allowed_sub_topics: Vec<String>,
         allowed_pub_topics: Vec<String>,
+        stats: Arc<MqttConnectionStats>,
+        shutdown: watch::Receiver<bool>,
         rt: tokio::runtime::Runtime,
     ) -> MqttConnection {
         let client = Arc::new(Mutex::new(client));
@@ -32,7 +143,83 @@ impl MqttConnection {
             events,
             allowed_sub_topics,
             allowed_pub_topics,
+            stats,
+            shutdown,
             rt,
+            publish_batch_sender: None,
+            dead_letter_topic: None,
+            shared_subscription_group: None,
+            subscriptions: Subscriptions::default(),
+            max_subscriptions: None,
+        }
+    }
+
+    pub fn subscriptions(&self) -> Subscriptions {
+        self.subscriptions.clone()
+    }
+
+    /// Caps the number of distinct topic filters the module can be subscribed to at once.
+    pub fn with_max_subscriptions(mut self, max_subscriptions: usize) -> MqttConnection {
+        self.max_subscriptions = Some(max_subscriptions);
+        self
+    }
+
+    /// Turns every `subscribe-sync` into a shared subscription in `group`, so the broker
+    /// spreads messages across all instances subscribing with the same group.
+    pub fn with_shared_subscription_group(mut self, group: String) -> MqttConnection {
+        self.shared_subscription_group = Some(group);
+        self
+    }
+
+    /// Enables `dead-letter`, which republishes to `topic`. The topic doesn't have to be
+    /// in `allowed_pub_topics`.
+    pub fn with_dead_letter_topic(mut self, topic: String) -> MqttConnection {
+        self.dead_letter_topic = Some(topic);
+        self
+    }
+
+    /// Routes `publish-sync` through a [`PublishBatchTask`] fed by `sender`.
+    pub fn with_publish_batching(
+        mut self,
+        sender: mpsc::Sender<OutboundPublish>,
+    ) -> MqttConnection {
+        self.publish_batch_sender = Some(sender);
+        self
+    }
+
+    /// The filter actually sent to the broker for a guest (un)subscribe of `topic`.
+    fn subscription_topic(&self, topic: &str) -> String {
+        match &self.shared_subscription_group {
+            Some(group) if shared_subscription_filter(topic) == topic => {
+                format!("$share/{}/{}", group, topic)
+            }
+            _ => topic.to_string(),
+        }
+    }
+
+    /// Runs `future` to completion unless the module is stopped first. `publish-sync` and
+    /// `subscribe-sync` are the cancellation points: they may wait for room in the event
+    /// loop's request queue, which never frees up if the broker is unreachable.
+    /// `poll-sync` and `mqtt-stats` never block and aren't cancellable.
+    fn block_on_cancellable<F: Future>(&self, future: F) -> Result<F::Output, String> {
+        let mut shutdown = self.shutdown.clone();
+
+        self.rt.block_on(async move {
+            tokio::select! {
+                output = future => Ok(output),
+                _ = wait_for_shutdown(&mut shutdown) => {
+                    Err("cancelled: module is shutting down".to_string())
+                }
+            }
+        })
+    }
+}
+
+async fn wait_for_shutdown(shutdown: &mut watch::Receiver<bool>) {
+    while !*shutdown.borrow() {
+        if shutdown.changed().await.is_err() {
+            // Nobody can request a shutdown anymore.
+            std::future::pending::<()>().await;
         }
     }
 }
@@ -55,15 +242,36 @@ impl mqtt::Mqtt for MqttConnection {
         retain: bool,
         payload: &[u8],
     ) -> Result<(), String> {
+        let _span = tracing::debug_span!("mqtt_publish", topic).entered();
         let client = &mut self.client.lock().unwrap();
 
-        if self.allowed_pub_topics.contains(&topic.to_string()) {
-            self.rt
-                .block_on(client.publish(topic, map_qos(qos), retain, payload))
-                .map_err(|e| format!("rumqttc error: '{}'", e))?;
+        if self
+            .allowed_pub_topics
+            .iter()
+            .any(|pattern| topic_matches(pattern, topic))
+        {
+            if let Some(publish_batch_sender) = &self.publish_batch_sender {
+                let publish = OutboundPublish {
+                    topic: topic.to_string(),
+                    qos: map_qos(qos),
+                    retain,
+                    payload: payload.to_vec(),
+                };
+
+                self.block_on_cancellable(publish_batch_sender.send(publish))?
+                    .map_err(|_| "publish batch task exited".to_string())?;
+            } else {
+                self.block_on_cancellable(client.publish(topic, map_qos(qos), retain, payload))?
+                    .map_err(|e| format!("rumqttc error: '{}'", e))?;
+            }
 
             Ok(())
         } else {
+            tracing::warn!(
+                "MQTT publish to topic '{}' denied by allowed_pub_topics",
+                topic
+            );
+
             Err(format!(
                 "publish to topic '{}' not allowed by config policy",
                 topic
@@ -72,14 +280,43 @@ impl mqtt::Mqtt for MqttConnection {
     }
 
     fn subscribe_sync(&mut self, topic: &str, qos: mqtt::QualityOfService) -> Result<(), String> {
+        let _span = tracing::debug_span!("mqtt_subscribe", topic).entered();
         let client = &mut self.client.lock().unwrap();
 
-        if self.allowed_sub_topics.contains(&topic.to_string()) {
-            self.rt
-                .block_on(client.subscribe(topic, map_qos(qos)))
+        // Shared subscriptions are checked against the filter they share, so
+        // `allowed_sub_topics` lists plain filters either way.
+        let filter = shared_subscription_filter(topic);
+        let topic = self.subscription_topic(topic);
+
+        if self
+            .allowed_sub_topics
+            .iter()
+            .any(|pattern| filter_covers(pattern, filter))
+        {
+            if let Some(max_subscriptions) = self.max_subscriptions {
+                let subscriptions = self.subscriptions.lock().unwrap();
+
+                if !subscriptions.contains_key(&topic) && subscriptions.len() >= max_subscriptions {
+                    return Err(format!(
+                        "subscribe to topic '{}' exceeds the limit of {} subscriptions",
+                        topic, max_subscriptions
+                    ));
+                }
+            }
+
+            self.block_on_cancellable(client.subscribe(&topic, map_qos(qos)))?
                 .map_err(|e| format!("rumqttc error: '{}'", e))?;
+            self.subscriptions
+                .lock()
+                .unwrap()
+                .insert(topic, map_qos(qos));
             Ok(())
         } else {
+            tracing::warn!(
+                "MQTT subscribe to topic '{}' denied by allowed_sub_topics",
+                topic
+            );
+
             Err(format!(
                 "subscribe to topic '{}' not allowed by config policy",
                 topic
@@ -87,6 +324,19 @@ impl mqtt::Mqtt for MqttConnection {
         }
     }
 
+    fn unsubscribe_sync(&mut self, topic: &str) -> Result<(), String> {
+        let _span = tracing::debug_span!("mqtt_unsubscribe", topic).entered();
+        let client = &mut self.client.lock().unwrap();
+
+        let topic = self.subscription_topic(topic);
+
+        self.block_on_cancellable(client.unsubscribe(&topic))?
+            .map_err(|e| format!("rumqttc error: '{}'", e))?;
+        self.subscriptions.lock().unwrap().remove(&topic);
+
+        Ok(())
+    }
+
     fn poll_sync(&mut self) -> Result<Vec<Result<mqtt::Event, String>>, String> {
         let mut events = vec![];
 
@@ -100,6 +350,7 @@ impl mqtt::Mqtt for MqttConnection {
                                 mqtt::IncomingEvent::Publish(mqtt::PublishEvent {
                                     topic: publish.topic,
                                     payload: publish.payload.to_vec(),
+                                    retain: publish.retain,
                                 }),
                             )),
                             _ => Err("unsupported event".to_string()),
@@ -118,9 +369,52 @@ impl mqtt::Mqtt for MqttConnection {
 
         Ok(events)
     }
+
+    fn mqtt_stats(&mut self) -> Result<mqtt::MqttStats, String> {
+        Ok(mqtt::MqttStats {
+            published: self.stats.published.load(Ordering::Relaxed),
+            received: self.stats.received.load(Ordering::Relaxed),
+            reconnects: self.stats.reconnects.load(Ordering::Relaxed),
+            last_error: self.stats.last_error(),
+            subscriptions: self.subscriptions.lock().unwrap().len() as u64,
+        })
+    }
+
+    fn publish_batching_enabled(&mut self) -> Result<bool, String> {
+        Ok(self.publish_batch_sender.is_some())
+    }
+
+    fn dead_letter(
+        &mut self,
+        original_topic: &str,
+        payload: &[u8],
+        reason: &str,
+    ) -> Result<(), String> {
+        let dead_letter_topic = self
+            .dead_letter_topic
+            .as_ref()
+            .ok_or_else(|| "no dead letter topic configured".to_string())?;
+
+        // MQTT 3.1.1 has no user properties, so the metadata travels in a JSON envelope.
+        let envelope = serde_json::json!({
+            "original_topic": original_topic,
+            "reason": reason,
+            "payload": base64::encode(payload),
+        });
+
+        let client = &mut self.client.lock().unwrap();
+
+        self.block_on_cancellable(client.publish(
+            dead_letter_topic,
+            rumqttc::QoS::AtLeastOnce,
+            false,
+            envelope.to_string(),
+        ))?
+        .map_err(|e| format!("rumqttc error: '{}'", e))
+    }
 }
 
-impl mqtt::Mqtt for Option<MqttConnection> {
+impl mqtt::Mqtt for WasmModuleStore {
     fn publish_sync(
         &mut self,
         topic: &str,
@@ -128,7 +422,9 @@ impl mqtt::Mqtt for Option<MqttConnection> {
         retain: bool,
         payload: &[u8],
     ) -> Result<(), String> {
-        if let Some(connection) = self {
+        self.host_calls.check("mqtt::publish-sync")?;
+
+        if let Some(connection) = &mut self.mqtt_connection {
             connection.publish_sync(topic, qos, retain, payload)
         } else {
             Err("Module does not have configured mqtt runtime".to_string())
@@ -136,16 +432,76 @@ impl mqtt::Mqtt for Option<MqttConnection> {
     }
 
     fn subscribe_sync(&mut self, topic: &str, qos: mqtt::QualityOfService) -> Result<(), String> {
-        if let Some(connection) = self {
+        self.host_calls.check("mqtt::subscribe-sync")?;
+
+        if let Some(connection) = &mut self.mqtt_connection {
             connection.subscribe_sync(topic, qos)
         } else {
             Err("Module does not have configured mqtt runtime".to_string())
         }
     }
 
+    fn unsubscribe_sync(&mut self, topic: &str) -> Result<(), String> {
+        self.host_calls.check("mqtt::unsubscribe-sync")?;
+
+        if let Some(connection) = &mut self.mqtt_connection {
+            connection.unsubscribe_sync(topic)
+        } else {
+            Err("Module does not have configured mqtt runtime".to_string())
+        }
+    }
+
     fn poll_sync(&mut self) -> Result<Vec<Result<mqtt::Event, String>>, String> {
-        if let Some(connection) = self {
-            connection.poll_sync()
+        self.host_calls.check("mqtt::poll-sync")?;
+
+        if let Some(connection) = &mut self.mqtt_connection {
+            let events = connection.poll_sync()?;
+
+            // An empty poll isn't activity, so a guest spinning on it still trips the
+            // watchdog.
+            if events
+                .iter()
+                .any(|event| matches!(event, Ok(mqtt::Event::Incoming(_))))
+            {
+                self.control.touch();
+            }
+
+            Ok(events)
+        } else {
+            Err("Module does not have configured mqtt runtime".to_string())
+        }
+    }
+
+    fn mqtt_stats(&mut self) -> Result<mqtt::MqttStats, String> {
+        self.host_calls.check("mqtt::mqtt-stats")?;
+
+        if let Some(connection) = &mut self.mqtt_connection {
+            connection.mqtt_stats()
+        } else {
+            Err("Module does not have configured mqtt runtime".to_string())
+        }
+    }
+
+    fn publish_batching_enabled(&mut self) -> Result<bool, String> {
+        self.host_calls.check("mqtt::publish-batching-enabled")?;
+
+        if let Some(connection) = &mut self.mqtt_connection {
+            connection.publish_batching_enabled()
+        } else {
+            Err("Module does not have configured mqtt runtime".to_string())
+        }
+    }
+
+    fn dead_letter(
+        &mut self,
+        original_topic: &str,
+        payload: &[u8],
+        reason: &str,
+    ) -> Result<(), String> {
+        self.host_calls.check("mqtt::dead-letter")?;
+
+        if let Some(connection) = &mut self.mqtt_connection {
+            connection.dead_letter(original_topic, payload, reason)
         } else {
             Err("Module does not have configured mqtt runtime".to_string())
         }
@@ -0,0 +1,330 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::mpsc;
+
+use crate::{
+    app::SubscriptionResult,
+    mqtt_api::Subscriptions,
+    topic::{shared_subscription_filter, topic_matches},
+};
+
+/// In-memory stand-in for an MQTT broker, shared by every module of an app context whose
+/// MQTT config sets `backend = "mock"`. Publishes are delivered to the matching
+/// subscriptions of all those modules, the publisher's own included, at the lower of
+/// the published and the subscribed QoS. Retained messages are kept per topic and
+/// delivered on subscribe. Shared subscriptions behave like plain ones. There is no
+/// network and no session state: every connection starts clean and is acknowledged
+/// right away, and QoS 1/2 publishes are acknowledged as soon as they're routed.
+#[derive(Clone, Default)]
+pub struct MockBroker {
+    inner: Arc<Mutex<BrokerState>>,
+}
+
+#[derive(Default)]
+struct BrokerState {
+    sessions: HashMap<u64, SessionEntry>,
+    next_session_id: u64,
+    retained: HashMap<String, rumqttc::Publish>,
+    published: Vec<MockMessage>,
+}
+
+struct SessionEntry {
+    filters: HashMap<String, rumqttc::QoS>,
+    inbox: mpsc::UnboundedSender<rumqttc::Publish>,
+}
+
+/// A message a module published to the [`MockBroker`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MockMessage {
+    pub client_id: String,
+    pub topic: String,
+    pub qos: rumqttc::QoS,
+    pub retain: bool,
+    pub payload: Vec<u8>,
+}
+
+impl MockBroker {
+    /// Delivers a message as if some other client had published it.
+    pub fn inject(&self, topic: &str, qos: rumqttc::QoS, retain: bool, payload: &[u8]) {
+        let mut publish = rumqttc::Publish::new(topic, qos, payload);
+        publish.retain = retain;
+
+        self.inner.lock().unwrap().route(publish);
+    }
+
+    /// Everything modules published since the last call, oldest first.
+    pub fn take_published(&self) -> Vec<MockMessage> {
+        std::mem::take(&mut self.inner.lock().unwrap().published)
+    }
+
+    /// Topic filters some module is subscribed to.
+    pub fn subscriptions(&self) -> Vec<String> {
+        let state = self.inner.lock().unwrap();
+        let mut filters: Vec<String> = state
+            .sessions
+            .values()
+            .flat_map(|session| session.filters.keys().cloned())
+            .collect();
+
+        filters.sort();
+        filters.dedup();
+        filters
+    }
+
+    pub(crate) fn connect(&self, client_id: &str) -> MockSession {
+        let (inbox, inbox_receiver) = mpsc::unbounded_channel();
+        let mut state = self.inner.lock().unwrap();
+        let id = state.next_session_id;
+
+        state.next_session_id += 1;
+        state.sessions.insert(
+            id,
+            SessionEntry {
+                filters: HashMap::new(),
+                inbox,
+            },
+        );
+
+        MockSession {
+            broker: self.clone(),
+            id,
+            client_id: client_id.to_string(),
+            inbox: inbox_receiver,
+            pending: VecDeque::from([rumqttc::Event::Incoming(rumqttc::Incoming::ConnAck(
+                rumqttc::ConnAck::new(rumqttc::ConnectReturnCode::Success, false),
+            ))]),
+            next_pkid: 1,
+        }
+    }
+}
+
+impl BrokerState {
+    fn route(&mut self, publish: rumqttc::Publish) {
+        if publish.retain {
+            if publish.payload.is_empty() {
+                self.retained.remove(&publish.topic);
+            } else {
+                self.retained.insert(publish.topic.clone(), publish.clone());
+            }
+        }
+
+        for session in self.sessions.values() {
+            let granted_qos = session
+                .filters
+                .iter()
+                .filter(|(filter, _)| {
+                    topic_matches(shared_subscription_filter(filter), &publish.topic)
+                })
+                .map(|(_, qos)| *qos)
+                .max_by_key(|qos| *qos as u8);
+
+            if let Some(granted_qos) = granted_qos {
+                let mut delivery = publish.clone();
+                delivery.qos = min_qos(publish.qos, granted_qos);
+                // Retain is only set on deliveries of stored messages.
+                delivery.retain = false;
+
+                let _ = session.inbox.send(delivery);
+            }
+        }
+    }
+}
+
+fn min_qos(a: rumqttc::QoS, b: rumqttc::QoS) -> rumqttc::QoS {
+    if (a as u8) <= (b as u8) {
+        a
+    } else {
+        b
+    }
+}
+
+/// One module's connection to the [`MockBroker`]. Takes the place of the network behind
+/// the module's rumqttc event loop: requests the client queues are read from the event
+/// loop's request channel and answered with the events a broker would cause.
+pub struct MockSession {
+    broker: MockBroker,
+    id: u64,
+    client_id: String,
+    inbox: mpsc::UnboundedReceiver<rumqttc::Publish>,
+    pending: VecDeque<rumqttc::Event>,
+    next_pkid: u16,
+}
+
+impl MockSession {
+    /// Counterpart of `rumqttc::EventLoop::poll`.
+    pub async fn poll(&mut self, event_loop: &mut rumqttc::EventLoop) -> rumqttc::Event {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return event;
+            }
+
+            tokio::select! {
+                request = event_loop.requests_rx.recv_async() => match request {
+                    Ok(request) => self.handle_request(request),
+                    // The event loop holds a sender itself, so this doesn't happen.
+                    Err(_) => std::future::pending().await,
+                },
+                Some(publish) = self.inbox.recv() => {
+                    return rumqttc::Event::Incoming(rumqttc::Incoming::Publish(publish));
+                }
+            }
+        }
+    }
+
+    /// Handles every request already queued, e.g. publishes on stop, so none is lost.
+    pub fn flush(&mut self, event_loop: &mut rumqttc::EventLoop) {
+        while let Ok(request) = event_loop.requests_rx.try_recv() {
+            self.handle_request(request);
+        }
+    }
+
+    /// Answer to [`crate::app::RuntimeEvent::Resubscribe`]: every filter is granted again.
+    pub fn resubscribe(&mut self, subscriptions: &Subscriptions) -> Vec<SubscriptionResult> {
+        let filters: Vec<rumqttc::SubscribeFilter> = subscriptions
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(topic, qos)| rumqttc::SubscribeFilter::new(topic.clone(), *qos))
+            .collect();
+
+        self.subscribe(&filters)
+            .into_iter()
+            .map(|(topic, qos)| SubscriptionResult {
+                topic,
+                granted_qos: Some(qos),
+            })
+            .collect()
+    }
+
+    fn pkid(&mut self) -> u16 {
+        let pkid = self.next_pkid;
+
+        self.next_pkid = self.next_pkid.checked_add(1).unwrap_or(1);
+        pkid
+    }
+
+    fn handle_request(&mut self, request: rumqttc::Request) {
+        use rumqttc::{Event, Incoming, Outgoing};
+
+        match request {
+            rumqttc::Request::Publish(publish) => {
+                let pkid = self.pkid();
+
+                self.pending
+                    .push_back(Event::Outgoing(Outgoing::Publish(pkid)));
+                match publish.qos {
+                    rumqttc::QoS::AtMostOnce => {}
+                    rumqttc::QoS::AtLeastOnce => self.pending.push_back(Event::Incoming(
+                        Incoming::PubAck(rumqttc::PubAck::new(pkid)),
+                    )),
+                    rumqttc::QoS::ExactlyOnce => {
+                        self.pending.push_back(Event::Incoming(Incoming::PubRec(
+                            rumqttc::PubRec::new(pkid),
+                        )));
+                        self.pending.push_back(Event::Incoming(Incoming::PubComp(
+                            rumqttc::PubComp::new(pkid),
+                        )));
+                    }
+                }
+
+                let mut state = self.broker.inner.lock().unwrap();
+                state.published.push(MockMessage {
+                    client_id: self.client_id.clone(),
+                    topic: publish.topic.clone(),
+                    qos: publish.qos,
+                    retain: publish.retain,
+                    payload: publish.payload.to_vec(),
+                });
+                state.route(publish);
+            }
+            rumqttc::Request::Subscribe(subscribe) => {
+                let pkid = self.pkid();
+                let return_codes = self
+                    .subscribe(&subscribe.filters)
+                    .into_iter()
+                    .map(|(_, qos)| rumqttc::SubscribeReasonCode::Success(qos))
+                    .collect();
+
+                self.pending
+                    .push_back(Event::Outgoing(Outgoing::Subscribe(pkid)));
+                self.pending
+                    .push_back(Event::Incoming(Incoming::SubAck(rumqttc::SubAck::new(
+                        pkid,
+                        return_codes,
+                    ))));
+                self.deliver_retained(&subscribe.filters);
+            }
+            rumqttc::Request::Unsubscribe(unsubscribe) => {
+                let pkid = self.pkid();
+                let mut state = self.broker.inner.lock().unwrap();
+
+                if let Some(session) = state.sessions.get_mut(&self.id) {
+                    for topic in &unsubscribe.topics {
+                        session.filters.remove(topic);
+                    }
+                }
+
+                self.pending
+                    .push_back(Event::Outgoing(Outgoing::Unsubscribe(pkid)));
+                self.pending.push_back(Event::Incoming(Incoming::UnsubAck(
+                    rumqttc::UnsubAck::new(pkid),
+                )));
+            }
+            rumqttc::Request::Disconnect => {
+                self.pending
+                    .push_back(Event::Outgoing(Outgoing::Disconnect));
+            }
+            // Acknowledgements and pings need no answer.
+            _ => {}
+        }
+    }
+
+    /// Adds the filters to this session and returns them with their granted QoS.
+    fn subscribe(&mut self, filters: &[rumqttc::SubscribeFilter]) -> Vec<(String, rumqttc::QoS)> {
+        let mut state = self.broker.inner.lock().unwrap();
+
+        if let Some(session) = state.sessions.get_mut(&self.id) {
+            for filter in filters {
+                session.filters.insert(filter.path.clone(), filter.qos);
+            }
+        }
+
+        filters
+            .iter()
+            .map(|filter| (filter.path.clone(), filter.qos))
+            .collect()
+    }
+
+    fn deliver_retained(&mut self, filters: &[rumqttc::SubscribeFilter]) {
+        let state = self.broker.inner.lock().unwrap();
+
+        for retained in state.retained.values() {
+            let granted_qos = filters
+                .iter()
+                .filter(|filter| {
+                    topic_matches(shared_subscription_filter(&filter.path), &retained.topic)
+                })
+                .map(|filter| filter.qos)
+                .max_by_key(|qos| *qos as u8);
+
+            if let Some(granted_qos) = granted_qos {
+                let mut delivery = retained.clone();
+                delivery.qos = min_qos(retained.qos, granted_qos);
+
+                self.pending
+                    .push_back(rumqttc::Event::Incoming(rumqttc::Incoming::Publish(
+                        delivery,
+                    )));
+            }
+        }
+    }
+}
+
+impl Drop for MockSession {
+    fn drop(&mut self) {
+        self.broker.inner.lock().unwrap().sessions.remove(&self.id);
+    }
+}
@@ -0,0 +1,247 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    extract::{Extension, Path, Query},
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    routing::{get, post},
+    Json, Router,
+};
+use schemars::JsonSchema;
+use serde_derive::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{app::ModuleStatus, handle::AppContextHandle, secret::Secret};
+
+#[derive(Deserialize, JsonSchema, Clone, Debug)]
+pub struct RestControlConfig {
+    pub bind_addr: SocketAddr,
+    /// Requests must carry `Authorization: Bearer <token>`. Without a token the API is
+    /// open to anyone who can reach `bind_addr`.
+    pub bearer_token: Option<Secret<String>>,
+    /// When `/readyz` reports ready. Defaults to `all`.
+    #[serde(default)]
+    pub readiness: ReadinessRule,
+}
+
+/// Modules that are disabled or were skipped as unavailable at startup don't count
+/// either way.
+#[derive(Deserialize, JsonSchema, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReadinessRule {
+    /// Every module is running.
+    #[default]
+    All,
+    /// At least one module is running.
+    Any,
+}
+
+struct RestState {
+    handle: AppContextHandle,
+    bearer_token: Option<Secret<String>>,
+    readiness: ReadinessRule,
+}
+
+type RestResult = Result<Json<Value>, (StatusCode, String)>;
+
+/// Serves the module lifecycle operations of `handle` over HTTP+JSON until the server
+/// fails:
+///
+/// - `GET /modules`: every module with its status
+/// - `GET /modules/{name}`: status of one module
+/// - `GET /modules/{name}/logs?n=50`: the module's most recent log lines
+/// - `POST /modules/{name}/start`, `POST /modules/{name}/stop`
+/// - `POST /modules/{name}/continue`: start a module held by `pause_on_start`
+/// - `GET /healthz`: liveness, 503 once the app context no longer responds
+/// - `GET /readyz`: readiness according to `readiness`, 503 if not ready
+///
+/// The probe endpoints don't require the bearer token and respond with `{"ok": bool}`
+/// only.
+///
+/// All commands go through [`AppContextHandle`], so this is only another front end for
+/// the same command handling.
+pub async fn serve(handle: AppContextHandle, config: RestControlConfig) -> anyhow::Result<()> {
+    let state = Arc::new(RestState {
+        handle,
+        bearer_token: config.bearer_token,
+        readiness: config.readiness,
+    });
+
+    let router = Router::new()
+        .route("/modules", get(list_modules))
+        .route("/modules/:name", get(module_status))
+        .route("/modules/:name/logs", get(recent_logs))
+        .route("/modules/:name/start", post(start_module))
+        .route("/modules/:name/stop", post(stop_module))
+        .route("/modules/:name/continue", post(continue_module))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .layer(Extension(state));
+
+    axum::Server::bind(&config.bind_addr)
+        .serve(router.into_make_service())
+        .await?;
+
+    Ok(())
+}
+
+fn authorize(state: &RestState, headers: &HeaderMap) -> Result<(), (StatusCode, String)> {
+    let bearer_token = match &state.bearer_token {
+        Some(bearer_token) => bearer_token,
+        None => return Ok(()),
+    };
+
+    let provided = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    if provided == Some(bearer_token.expose().as_str()) {
+        Ok(())
+    } else {
+        Err((StatusCode::UNAUTHORIZED, "invalid bearer token".to_string()))
+    }
+}
+
+fn internal_error(error: anyhow::Error) -> (StatusCode, String) {
+    (StatusCode::INTERNAL_SERVER_ERROR, error.to_string())
+}
+
+async fn list_modules(
+    Extension(state): Extension<Arc<RestState>>,
+    headers: HeaderMap,
+) -> RestResult {
+    authorize(&state, &headers)?;
+
+    let modules = state.handle.list_modules().await.map_err(internal_error)?;
+
+    Ok(Json(json!(modules
+        .into_iter()
+        .map(|(name, status)| json!({ "name": name, "status": status }))
+        .collect::<Vec<_>>())))
+}
+
+async fn module_status(
+    Extension(state): Extension<Arc<RestState>>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+) -> RestResult {
+    authorize(&state, &headers)?;
+
+    match state
+        .handle
+        .module_status(&name)
+        .await
+        .map_err(internal_error)?
+    {
+        Some(status) => Ok(Json(json!({ "name": name, "status": status }))),
+        None => Err((StatusCode::NOT_FOUND, format!("unknown module '{}'", name))),
+    }
+}
+
+#[derive(Deserialize)]
+struct RecentLogsQuery {
+    n: Option<usize>,
+}
+
+async fn recent_logs(
+    Extension(state): Extension<Arc<RestState>>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    Query(query): Query<RecentLogsQuery>,
+) -> RestResult {
+    authorize(&state, &headers)?;
+
+    let logs = state
+        .handle
+        .recent_logs(&name, query.n.unwrap_or(usize::MAX))
+        .await
+        .map_err(internal_error)?;
+
+    Ok(Json(json!(logs)))
+}
+
+async fn start_module(
+    Extension(state): Extension<Arc<RestState>>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+) -> RestResult {
+    authorize(&state, &headers)?;
+
+    state
+        .handle
+        .run_module(&name)
+        .await
+        .map_err(|e| (StatusCode::CONFLICT, format!("{:#}", e)))?;
+
+    Ok(Json(json!({ "name": name })))
+}
+
+async fn stop_module(
+    Extension(state): Extension<Arc<RestState>>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+) -> RestResult {
+    authorize(&state, &headers)?;
+
+    let module_exit = state
+        .handle
+        .stop_module(&name)
+        .await
+        .map_err(|e| (StatusCode::CONFLICT, e.to_string()))?;
+
+    Ok(Json(json!({
+        "name": name,
+        "exit": module_exit.map(|module_exit| format!("{:?}", module_exit)),
+    })))
+}
+
+async fn continue_module(
+    Extension(state): Extension<Arc<RestState>>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+) -> RestResult {
+    authorize(&state, &headers)?;
+
+    state
+        .handle
+        .continue_module(&name)
+        .await
+        .map_err(|e| (StatusCode::CONFLICT, e.to_string()))?;
+
+    Ok(Json(json!({ "name": name })))
+}
+
+/// Probes are unauthenticated, so they only say whether the check passed.
+fn probe_response(ok: bool) -> (StatusCode, Json<Value>) {
+    let status_code = if ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (status_code, Json(json!({ "ok": ok })))
+}
+
+/// Fails only when the app context task no longer answers. Failed modules are left to
+/// `/readyz`, so one bad module doesn't get the whole process restarted.
+async fn healthz(Extension(state): Extension<Arc<RestState>>) -> (StatusCode, Json<Value>) {
+    probe_response(state.handle.list_modules().await.is_ok())
+}
+
+async fn readyz(Extension(state): Extension<Arc<RestState>>) -> (StatusCode, Json<Value>) {
+    let modules = match state.handle.list_modules().await {
+        Ok(modules) => modules,
+        Err(_) => return probe_response(false),
+    };
+
+    let mut counted = modules.iter().filter(|(_, status)| {
+        !matches!(status, ModuleStatus::Disabled | ModuleStatus::Unavailable)
+    });
+
+    let ready = match state.readiness {
+        ReadinessRule::All => counted.all(|(_, status)| *status == ModuleStatus::Running),
+        ReadinessRule::Any => counted.any(|(_, status)| *status == ModuleStatus::Running),
+    };
+
+    probe_response(ready)
+}
@@ -0,0 +1,58 @@
+use wit_bindgen_host_wasmtime_rust::export;
+
+export!("./wit-bindgen/runtime.wit");
+
+pub use runtime::add_to_linker;
+
+use crate::module::WasmModuleStore;
+
+impl runtime::Runtime for WasmModuleStore {
+    fn heartbeat(&mut self) {
+        if self.host_calls.check("runtime::heartbeat").is_err() {
+            return;
+        }
+
+        self.control.touch();
+    }
+
+    fn mark_ready(&mut self) {
+        if self.host_calls.check("runtime::mark-ready").is_err() {
+            return;
+        }
+
+        self.control.mark_ready();
+    }
+
+    fn request_restart(&mut self, reason: &str) {
+        if self.host_calls.check("runtime::request-restart").is_err() {
+            return;
+        }
+
+        self.control.request_restart(reason);
+    }
+
+    fn set_instance_label(&mut self, label: &str) {
+        if self
+            .host_calls
+            .check("runtime::set-instance-label")
+            .is_err()
+        {
+            return;
+        }
+
+        self.control.set_instance_label(label);
+    }
+
+    fn feature_enabled(&mut self, name: &str) -> bool {
+        if self.host_calls.check("runtime::feature-enabled").is_err() {
+            return false;
+        }
+
+        self.feature_flags
+            .read()
+            .unwrap()
+            .get(name)
+            .copied()
+            .unwrap_or(false)
+    }
+}
@@ -0,0 +1,45 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use tokio_stream::StreamExt;
+
+use crate::app::{app_event_stream, AppEvent};
+
+/// Destination for [`AppEvent`]s, registered with
+/// [`crate::app::InitializedAppContext::add_event_sink`]. Each sink is fed by its own
+/// task in event order, so a slow sink only delays itself; if it falls too far behind it
+/// receives an [`AppEvent::Lagged`] in place of the events it missed.
+#[async_trait]
+pub trait EventSink: Send + Sync {
+    async fn on_event(&self, event: &AppEvent);
+}
+
+/// Emits every event as an info-level `tracing` event, with the event itself in the
+/// `event` field, so events end up wherever the embedder's subscriber sends its logs.
+pub struct TracingSink;
+
+#[async_trait]
+impl EventSink for TracingSink {
+    async fn on_event(&self, event: &AppEvent) {
+        tracing::info!(event = ?event, "app event");
+    }
+}
+
+/// Discards every event.
+pub struct NullSink;
+
+#[async_trait]
+impl EventSink for NullSink {
+    async fn on_event(&self, _event: &AppEvent) {}
+}
+
+pub(crate) async fn event_sink_task(
+    sink: Arc<dyn EventSink>,
+    receiver: tokio::sync::broadcast::Receiver<AppEvent>,
+) {
+    let mut events = Box::pin(app_event_stream(receiver));
+
+    while let Some(event) = events.next().await {
+        sink.on_event(&event).await;
+    }
+}
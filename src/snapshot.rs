@@ -0,0 +1,71 @@
+use std::{
+    collections::BTreeMap,
+    sync::atomic::Ordering,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde_derive::Serialize;
+
+use crate::{app::ModuleStatus, module::ModuleRuntimeConfig, mqtt_api::MqttConnectionStats};
+
+/// Read-only view of the whole runtime, see [`crate::app::InitializedAppContext::snapshot`].
+#[derive(Serialize, Debug, Clone)]
+pub struct RuntimeSnapshot {
+    /// Milliseconds since the Unix epoch.
+    pub taken_at_ms: u64,
+    pub paused: bool,
+    /// Tokio tasks of all running modules, see `max_background_tasks`.
+    pub background_tasks: usize,
+    pub max_background_tasks: Option<usize>,
+    pub modules: BTreeMap<String, ModuleSnapshot>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ModuleSnapshot {
+    pub status: ModuleStatus,
+    pub group: Option<String>,
+    /// Milliseconds since the Unix epoch, `None` if the module isn't running.
+    pub started_at_ms: Option<u64>,
+    pub uptime_ms: Option<u64>,
+    /// See [`crate::app::InitializedAppContext::instance_label`].
+    pub instance_label: Option<String>,
+    pub restart_count: u64,
+    pub last_failure: Option<String>,
+    pub start_error: Option<String>,
+    /// Why the last [`crate::app::InitializedAppContext::canary_start`] failed.
+    pub canary_error: Option<String>,
+    /// Host calls refused by `allowed_host_calls`, over all starts.
+    pub denied_host_calls: u64,
+    /// `None` if the module isn't running or has no MQTT connection.
+    pub mqtt: Option<MqttSnapshot>,
+    /// Effective runtime config, with secrets redacted.
+    pub config: ModuleRuntimeConfig,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct MqttSnapshot {
+    pub connected: bool,
+    pub published: u64,
+    pub received: u64,
+    pub reconnects: u64,
+    pub deduplicated: u64,
+    pub last_error: Option<String>,
+}
+
+impl MqttSnapshot {
+    pub(crate) fn from_stats(stats: &MqttConnectionStats) -> MqttSnapshot {
+        MqttSnapshot {
+            connected: *stats.connected.borrow(),
+            published: stats.published.load(Ordering::Relaxed),
+            received: stats.received.load(Ordering::Relaxed),
+            reconnects: stats.reconnects.load(Ordering::Relaxed),
+            deduplicated: stats.deduplicated.load(Ordering::Relaxed),
+            last_error: stats.last_error(),
+        }
+    }
+}
+
+pub(crate) fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_millis() as u64)
+}
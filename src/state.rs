@@ -0,0 +1,46 @@
+use std::{collections::HashMap, path::Path};
+
+use serde_derive::{Deserialize, Serialize};
+
+/// Start/failure history of a module. Persisted across runtime restarts when the app
+/// config sets `state_file`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ModuleRestartStats {
+    pub start_count: u64,
+    pub last_failure: Option<String>,
+}
+
+impl ModuleRestartStats {
+    pub fn restart_count(&self) -> u64 {
+        self.start_count.saturating_sub(1)
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct PersistedState {
+    #[serde(default)]
+    modules: HashMap<String, ModuleRestartStats>,
+}
+
+pub fn load_restart_stats(path: &Path) -> anyhow::Result<HashMap<String, ModuleRestartStats>> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => Ok(toml::from_str::<PersistedState>(&contents)?.modules),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn save_restart_stats(
+    path: &Path,
+    modules: HashMap<String, ModuleRestartStats>,
+) -> anyhow::Result<()> {
+    let contents = toml::to_string(&PersistedState { modules })?;
+
+    // Write a sibling file and rename it over the old state so a crash mid-write can't
+    // leave a truncated state file behind.
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, contents)?;
+    std::fs::rename(tmp_path, path)?;
+
+    Ok(())
+}
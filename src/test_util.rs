@@ -0,0 +1,87 @@
+use rumqttc::{Event, Incoming, Publish, QoS};
+use tokio::sync::broadcast::{self, error::TryRecvError};
+
+use crate::app::{AppEvent, InitializedAppContext, ModuleExit, ModuleStatus};
+
+/// Wraps an [`InitializedAppContext`] with the hooks integration tests need but the
+/// production API deliberately doesn't offer.
+pub struct TestHarness {
+    context: InitializedAppContext,
+    events: broadcast::Receiver<AppEvent>,
+}
+
+impl TestHarness {
+    pub fn new(context: InitializedAppContext) -> TestHarness {
+        let events = context.subscribe_events();
+
+        TestHarness { context, events }
+    }
+
+    pub fn context(&self) -> &InitializedAppContext {
+        &self.context
+    }
+
+    pub fn context_mut(&mut self) -> &mut InitializedAppContext {
+        &mut self.context
+    }
+
+    /// Starts the module and waits for its `start` entrypoint to return.
+    pub async fn run_and_wait(&mut self, module_name: &str) -> anyhow::Result<ModuleExit> {
+        self.context.run_module(module_name)?;
+        self.context.teardown_module(module_name).await
+    }
+
+    pub fn assert_status(&self, module_name: &str, expected: ModuleStatus) {
+        assert_eq!(
+            self.context.module_status(module_name),
+            Some(expected),
+            "unexpected status for module '{}'",
+            module_name
+        );
+    }
+
+    /// Delivers a message to a running module as if its broker had sent it.
+    pub async fn send_mqtt(
+        &self,
+        module_name: &str,
+        topic: &str,
+        payload: &[u8],
+    ) -> anyhow::Result<()> {
+        let publish = Publish::new(topic, QoS::AtMostOnce, payload.to_vec());
+
+        self.context
+            .inject_mqtt_event(module_name, Event::Incoming(Incoming::Publish(publish)))
+            .await
+    }
+
+    /// Like [`TestHarness::send_mqtt`], but flagged as a retained message.
+    pub async fn send_retained_mqtt(
+        &self,
+        module_name: &str,
+        topic: &str,
+        payload: &[u8],
+    ) -> anyhow::Result<()> {
+        let mut publish = Publish::new(topic, QoS::AtMostOnce, payload.to_vec());
+        publish.retain = true;
+
+        self.context
+            .inject_mqtt_event(module_name, Event::Incoming(Incoming::Publish(publish)))
+            .await
+    }
+
+    /// Returns the events published since the harness was created or since the previous
+    /// call.
+    pub fn take_events(&mut self) -> Vec<AppEvent> {
+        let mut events = vec![];
+
+        loop {
+            match self.events.try_recv() {
+                Ok(event) => events.push(event),
+                Err(TryRecvError::Lagged(skipped)) => events.push(AppEvent::Lagged { skipped }),
+                Err(TryRecvError::Empty) | Err(TryRecvError::Closed) => break,
+            }
+        }
+
+        events
+    }
+}
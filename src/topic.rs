@@ -0,0 +1,81 @@
+/// Checks an MQTT topic against a topic filter, honoring the `+` (single level) and `#`
+/// (remaining levels) wildcards.
+pub fn topic_matches(topic_filter: &str, topic: &str) -> bool {
+    let mut filter_levels = topic_filter.split('/');
+    let mut topic_levels = topic.split('/');
+
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(filter_level), Some(topic_level)) if filter_level == topic_level => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// Checks whether every topic matched by `topic_filter` is also matched by `pattern`,
+/// i.e. whether subscribing to `topic_filter` stays within what `pattern` allows.
+pub fn filter_covers(pattern: &str, topic_filter: &str) -> bool {
+    let mut pattern_levels = pattern.split('/');
+    let mut filter_levels = topic_filter.split('/');
+
+    loop {
+        match (pattern_levels.next(), filter_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(filter_level)) if filter_level != "#" => {}
+            (Some(pattern_level), Some(filter_level)) if pattern_level == filter_level => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// Strips the `$share/<group>/` prefix of a shared subscription, leaving the topic filter
+/// the broker matches messages against. Other filters are returned unchanged.
+pub fn shared_subscription_filter(topic_filter: &str) -> &str {
+    topic_filter
+        .strip_prefix("$share/")
+        .and_then(|rest| rest.split_once('/'))
+        .map_or(topic_filter, |(_, filter)| filter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn topic_matches_wildcards() {
+        assert!(topic_matches("a/b", "a/b"));
+        assert!(!topic_matches("a/b", "a/c"));
+        assert!(!topic_matches("a/b", "a/b/c"));
+        assert!(!topic_matches("a/b/c", "a/b"));
+
+        assert!(topic_matches("a/+/c", "a/b/c"));
+        assert!(topic_matches("a/+", "a/"));
+        assert!(!topic_matches("a/+", "a"));
+        assert!(!topic_matches("a/+", "a/b/c"));
+
+        assert!(topic_matches("#", "a/b/c"));
+        assert!(topic_matches("a/#", "a/b/c"));
+        assert!(topic_matches("a/#", "a"));
+        assert!(!topic_matches("a/#", "b/c"));
+    }
+
+    #[test]
+    fn filter_covers_only_narrower_filters() {
+        assert!(filter_covers("a/b", "a/b"));
+        assert!(!filter_covers("a/b", "a/+"));
+
+        assert!(filter_covers("a/+", "a/b"));
+        assert!(filter_covers("a/+", "a/+"));
+        assert!(!filter_covers("a/+", "a/#"));
+        assert!(!filter_covers("a/+", "a/b/c"));
+
+        assert!(filter_covers("a/#", "a/b/#"));
+        assert!(filter_covers("a/#", "a/+/c"));
+        assert!(filter_covers("#", "#"));
+        assert!(!filter_covers("a/b/#", "a/#"));
+    }
+}